@@ -0,0 +1,29 @@
+@group(0) @binding(0) var screen_texture: texture_2d<f32>;
+@group(0) @binding(1) var mask_texture: texture_storage_2d<rgba8uint, write>;
+
+// Each invocation packs the braille masks of four horizontally adjacent cells into one texel
+@compute @workgroup_size(8, 8, 1)
+fn pack_masks(@builtin(global_invocation_id) id: vec3<u32>) {
+    let mask_size = textureDimensions(mask_texture);
+    if (id.x >= mask_size.x || id.y >= mask_size.y) {
+        return;
+    }
+
+    // Bit of each dot in a braille character, indexed by x * 4 + y
+    var dot_bits = array<u32, 8>(0u, 1u, 2u, 6u, 3u, 4u, 5u, 7u);
+
+    let screen_size = textureDimensions(screen_texture);
+    var masks = vec4<u32>(0u);
+    for (var cell = 0u; cell < 4u; cell++) {
+        var mask = 0u;
+        for (var dot = 0u; dot < 8u; dot++) {
+            let position = vec2<u32>((id.x * 4u + cell) * 2u + dot / 4u, id.y * 4u + dot % 4u);
+            if (all(position < screen_size) && textureLoad(screen_texture, position, 0).r >= 1.0) {
+                mask |= 1u << dot_bits[dot];
+            }
+        }
+        masks[cell] = mask;
+    }
+
+    textureStore(mask_texture, id.xy, masks);
+}
@@ -0,0 +1,211 @@
+use std::{any::Any, io};
+
+use ratatui::{
+    backend::{Backend, ClearType, WindowSize},
+    buffer::Cell,
+    layout::{Position, Size},
+};
+
+/// Object safe counterpart of [`Backend`], implemented for every backend, so that backends of any
+/// type can be boxed
+pub(crate) trait DynBackend: Send + Sync {
+    fn draw(&mut self, content: &mut dyn Iterator<Item = (u16, u16, &Cell)>) -> io::Result<()>;
+    fn hide_cursor(&mut self) -> io::Result<()>;
+    fn show_cursor(&mut self) -> io::Result<()>;
+    fn get_cursor_position(&mut self) -> io::Result<Position>;
+    fn set_cursor_position(&mut self, position: Position) -> io::Result<()>;
+    fn clear(&mut self) -> io::Result<()>;
+    fn clear_region(&mut self, clear_type: ClearType) -> io::Result<()>;
+    fn size(&self) -> io::Result<Size>;
+    fn window_size(&mut self) -> io::Result<WindowSize>;
+    fn flush(&mut self) -> io::Result<()>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<B: Backend + Send + Sync + 'static> DynBackend for B {
+    fn draw(&mut self, content: &mut dyn Iterator<Item = (u16, u16, &Cell)>) -> io::Result<()> {
+        Backend::draw(self, content)
+    }
+
+    fn hide_cursor(&mut self) -> io::Result<()> {
+        Backend::hide_cursor(self)
+    }
+
+    fn show_cursor(&mut self) -> io::Result<()> {
+        Backend::show_cursor(self)
+    }
+
+    fn get_cursor_position(&mut self) -> io::Result<Position> {
+        Backend::get_cursor_position(self)
+    }
+
+    fn set_cursor_position(&mut self, position: Position) -> io::Result<()> {
+        Backend::set_cursor_position(self, position)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        Backend::clear(self)
+    }
+
+    fn clear_region(&mut self, clear_type: ClearType) -> io::Result<()> {
+        Backend::clear_region(self, clear_type)
+    }
+
+    fn size(&self) -> io::Result<Size> {
+        Backend::size(self)
+    }
+
+    fn window_size(&mut self) -> io::Result<WindowSize> {
+        Backend::window_size(self)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Backend::flush(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Ratatui backend of any type, which frames are drawn to directly instead of being written to a
+/// terminal. Use [`TerminalTarget::backend`](crate::TerminalTarget::backend) to draw to one.
+pub struct BoxedBackend(Box<dyn DynBackend>);
+
+impl BoxedBackend {
+    /// Boxes the given backend
+    pub fn new(backend: impl Backend + Send + Sync + 'static) -> Self {
+        Self(Box::new(backend))
+    }
+
+    /// The backend, if it is of the given type
+    pub fn downcast_ref<B: Backend + 'static>(&self) -> Option<&B> {
+        self.0.as_any().downcast_ref()
+    }
+
+    /// The backend, mutably, if it is of the given type
+    pub fn downcast_mut<B: Backend + 'static>(&mut self) -> Option<&mut B> {
+        self.0.as_any_mut().downcast_mut()
+    }
+}
+
+impl Backend for BoxedBackend {
+    fn draw<'a, I>(&mut self, mut content: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (u16, u16, &'a Cell)>,
+    {
+        self.0.draw(&mut content)
+    }
+
+    fn hide_cursor(&mut self) -> io::Result<()> {
+        self.0.hide_cursor()
+    }
+
+    fn show_cursor(&mut self) -> io::Result<()> {
+        self.0.show_cursor()
+    }
+
+    fn get_cursor_position(&mut self) -> io::Result<Position> {
+        self.0.get_cursor_position()
+    }
+
+    fn set_cursor_position<P: Into<Position>>(&mut self, position: P) -> io::Result<()> {
+        self.0.set_cursor_position(position.into())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.0.clear()
+    }
+
+    fn clear_region(&mut self, clear_type: ClearType) -> io::Result<()> {
+        self.0.clear_region(clear_type)
+    }
+
+    fn size(&self) -> io::Result<Size> {
+        self.0.size()
+    }
+
+    fn window_size(&mut self) -> io::Result<WindowSize> {
+        self.0.window_size()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{input::keyboard::KeyboardInput, prelude::*};
+    use crossterm::event::{Event, KeyCode as TermKeyCode, KeyEvent, KeyModifiers};
+    use ratatui::{backend::TestBackend, layout::Rect, widgets::Paragraph, Frame};
+
+    use crate::{
+        display::resources::{PrimaryTerminal, Terminal},
+        testing::snapshot::SnapshotApp,
+        widgets::{components::Widget, TerminalWidget},
+        TerminalDisplayPlugin, TerminalTarget,
+    };
+
+    /// Widget drawing the same text every frame
+    struct Label;
+
+    impl TerminalWidget for Label {
+        fn render(&mut self, frame: &mut Frame, rect: Rect) {
+            frame.render_widget(Paragraph::new("backend"), rect);
+        }
+    }
+
+    #[test]
+    fn custom_backend_is_drawn_to_and_resized() {
+        let mut app = SnapshotApp::with_plugin(TerminalDisplayPlugin {
+            target: TerminalTarget::test(10, 2),
+            log_path: std::env::temp_dir().join("bevy_terminal_display_backend.log"),
+            ..default()
+        });
+        app.app_mut().world_mut().spawn(Widget {
+            widget: Box::new(Label),
+            depth: 0,
+            enabled: true,
+        });
+        app.step(1);
+
+        let world = app.app_mut().world_mut();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        let mut terminal = world
+            .get_mut::<Terminal>(primary)
+            .expect("Primary terminal does not exist");
+        assert!(terminal.is_headless());
+        terminal
+            .backend::<TestBackend>()
+            .expect("Terminal does not draw to a TestBackend")
+            .assert_buffer_lines(["backend   ", "          "]);
+        terminal
+            .backend_mut::<TestBackend>()
+            .expect("Terminal does not draw to a TestBackend")
+            .resize(4, 1);
+        terminal.inject_input(Event::Key(KeyEvent::new(
+            TermKeyCode::Char('a'),
+            KeyModifiers::NONE,
+        )));
+        app.step(1);
+
+        let world = app.app_mut().world_mut();
+        world
+            .get::<Terminal>(primary)
+            .and_then(Terminal::backend::<TestBackend>)
+            .expect("Terminal does not draw to a TestBackend")
+            .assert_buffer_lines(["back"]);
+        let keys = world
+            .resource_mut::<Events<KeyboardInput>>()
+            .drain()
+            .map(|event| event.key_code)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [KeyCode::KeyA]);
+    }
+}
@@ -1,29 +1,236 @@
+use std::sync::{Arc, Mutex};
+
 use bevy::{
-    ecs::{
-        component::ComponentId,
-        world::DeferredWorld,
-    },
+    ecs::{component::ComponentId, world::DeferredWorld},
     prelude::*,
-    render::render_resource::{
-        Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+    render::{
+        extract_component::ExtractComponent,
+        render_resource::{
+            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+        },
     },
 };
 use bevy_dither_post_process::components::DitherPostProcessSettings;
-use bevy_headless_render::components::HeadlessRenderSource;
+use bevy_headless_render::components::{HeadlessRenderDestination, HeadlessRenderSource};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Rect, Size},
+};
+
+use super::{
+    encoding::{self, Encoder},
+    resources::{PrimaryTerminal, Terminal},
+};
 
 /// Marker component for terminal display
 #[derive(Component, Debug)]
 #[component(on_add = on_add_terminal_display)]
 pub struct TerminalDisplay(pub u32);
 
+/// Assigns a display or widget to the terminal output entity it is drawn on, and whose input it
+/// receives. Entities without this component use the
+/// [`PrimaryTerminal`](super::resources::PrimaryTerminal).
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnTerminal(pub Entity);
+
+/// Marker component which, when inserted alongside [`TerminalDisplay`], packs braille masks in a
+/// compute shader after the dither post-process, so that only one byte per character cell has to
+/// be read back from the GPU. Like the dither post-process, the pass only runs for 3D cameras.
+#[derive(Component, Debug, Default)]
+pub struct GpuBrailleEncoding;
+
+/// Render-world target of the braille mask compute pass for a display
+#[derive(Component, ExtractComponent, Clone)]
+pub(crate) struct BrailleMaskTarget(pub(crate) Handle<Image>);
+
+/// Shared handle to the rendered output of a terminal display. Inserted automatically alongside
+/// [`TerminalDisplay`], and used to construct [`CameraView`](crate::widgets::camera_view::CameraView)
+/// widgets.
+#[derive(Component, Clone)]
+pub struct TerminalDisplayOutput {
+    pub(crate) entity: Entity,
+    pub(crate) destination: HeadlessRenderDestination,
+    pub(crate) frames: Arc<Mutex<DisplayFrames>>,
+    pub(crate) viewport: Arc<Mutex<Option<UVec2>>>,
+    pub(crate) render_target: Handle<Image>,
+    pub(crate) mask_target: Option<Handle<Image>>,
+}
+
+/// Frames read back from the GPU for a display. New frames are taken out of the headless render
+/// destination as soon as they are complete, so that encoding never holds the destination's lock
+/// while the render world is writing to it.
+#[derive(Default)]
+pub(crate) struct DisplayFrames {
+    /// Most recent complete frame
+    latest: Option<Image>,
+    /// Sequence number of the most recent complete frame
+    sequence: u64,
+    /// Sequence number of the most recent frame which was drawn to the terminal
+    drawn_sequence: u64,
+    /// Cells encoded from a frame, along with the sequence number of that frame, the area they
+    /// were encoded for and the area which was written
+    encoded: Option<(u64, Rect, Rect, Buffer)>,
+}
+
+impl DisplayFrames {
+    /// Takes a newly completed frame out of the given destination, if one is available and the
+    /// destination is not currently being written to. Frames identical to the previous one are
+    /// discarded, so the sequence number only increases when the display's contents change.
+    fn receive(&mut self, destination: &HeadlessRenderDestination, masks: bool) {
+        let Ok(mut image) = destination.0.try_lock() else {
+            return;
+        };
+        if image.data.is_empty() {
+            return;
+        }
+
+        let mut frame = std::mem::replace(&mut *image, empty_image());
+        drop(image);
+        if !masks {
+            encoding::ensure_r8unorm(&mut frame);
+        }
+        if self
+            .latest
+            .as_ref()
+            .is_some_and(|latest| latest.size() == frame.size() && latest.data == frame.data)
+        {
+            return;
+        }
+        self.latest = Some(frame);
+        self.sequence += 1;
+    }
+}
+
+/// Image with no data, used to mark a headless render destination as consumed
+fn empty_image() -> Image {
+    Image {
+        data: Vec::new(),
+        ..default()
+    }
+}
+
+impl TerminalDisplayOutput {
+    /// Entity of the display this output belongs to
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    /// Whether this display is currently embedded in a widget layout rather than drawn fullscreen
+    pub fn is_embedded(&self) -> bool {
+        self.viewport
+            .lock()
+            .expect("Failed to get lock on display viewport")
+            .is_some()
+    }
+
+    /// Sequence number of the most recent complete frame received from the GPU. Increases by one
+    /// for every new frame, and is zero until the first frame arrives.
+    pub fn frame_sequence(&self) -> u64 {
+        self.frames
+            .lock()
+            .expect("Failed to get lock on display frames")
+            .sequence
+    }
+
+    /// Takes the most recent complete frame out of the headless render destination, if a new one
+    /// is available
+    pub(crate) fn receive(&self) {
+        self.frames
+            .lock()
+            .expect("Failed to get lock on display frames")
+            .receive(&self.destination, self.mask_target.is_some());
+    }
+
+    /// Whether a frame with different contents has been received since the terminal was last drawn
+    pub(crate) fn has_new_frame(&self) -> bool {
+        let frames = self
+            .frames
+            .lock()
+            .expect("Failed to get lock on display frames");
+        frames.sequence != frames.drawn_sequence
+    }
+
+    /// Records that the most recent frame has been drawn to the terminal
+    pub(crate) fn mark_drawn(&self) {
+        let mut frames = self
+            .frames
+            .lock()
+            .expect("Failed to get lock on display frames");
+        frames.drawn_sequence = frames.sequence;
+    }
+
+    /// Draws the most recent complete frame of this display into the given area of a buffer. The
+    /// frame is only encoded again if it or the area have changed since it was last drawn.
+    pub(crate) fn draw(&self, area: Rect, buffer: &mut Buffer) {
+        let area = area.intersection(buffer.area);
+        let mut frames = self
+            .frames
+            .lock()
+            .expect("Failed to get lock on display frames");
+
+        let sequence = frames.sequence;
+        let DisplayFrames {
+            latest, encoded, ..
+        } = &mut *frames;
+        let Some(latest) = latest else {
+            return;
+        };
+        let up_to_date = encoded
+            .as_ref()
+            .is_some_and(|(encoded_sequence, encoded_area, _, _)| {
+                *encoded_sequence == sequence && *encoded_area == area
+            });
+        if !up_to_date {
+            // Reuse the previous cell buffer's allocation where possible
+            let mut cells = encoded
+                .take()
+                .map(|(_, _, _, cells)| cells)
+                .unwrap_or_else(|| Buffer::empty(area));
+            cells.resize(area);
+            cells.reset();
+            let written = if self.mask_target.is_some() {
+                encoding::decode_braille_masks(latest, area, &mut cells)
+            } else {
+                // Frames rendered at reduced resolution are scaled up to fill the area
+                let size = UVec2::new(area.width as u32, area.height as u32)
+                    * Encoder::Braille.cell_size();
+                if latest.size() != size && size.x > 0 && size.y > 0 {
+                    Encoder::Braille.encode(&encoding::scale(latest, size), area, &mut cells)
+                } else {
+                    Encoder::Braille.encode(latest, area, &mut cells)
+                }
+            };
+            *encoded = Some((sequence, area, written, cells));
+        }
+
+        let Some((_, _, written, cells)) = encoded else {
+            return;
+        };
+        for y in written.top()..written.bottom() {
+            let source = cells.index_of(written.x, y);
+            let destination = buffer.index_of(written.x, y);
+            let width = written.width as usize;
+            buffer.content[destination..destination + width]
+                .clone_from_slice(&cells.content[source..source + width]);
+        }
+    }
+}
+
 fn on_add_terminal_display(mut world: DeferredWorld, entity: Entity, _id: ComponentId) {
     let asset_server = world.get_resource::<AssetServer>().unwrap();
     let dither_level = world.entity(entity).get::<TerminalDisplay>().unwrap().0;
 
-    let terminal_size = crossterm::terminal::size().unwrap();
+    // The display is resized once its terminal exists, if it does not yet
+    let terminal = world
+        .resource::<PrimaryTerminal>()
+        .resolve(world.entity(entity).get::<OnTerminal>());
+    let terminal_size = world
+        .get::<Terminal>(terminal)
+        .and_then(|terminal| terminal.area().ok())
+        .map_or(Size::new(1, 1), |area| area.as_size());
     let size = Extent3d {
-        width: (terminal_size.0 as u32) * 2,
-        height: (terminal_size.1 as u32) * 4,
+        width: (terminal_size.width as u32) * 2,
+        height: (terminal_size.height as u32) * 4,
         depth_or_array_layers: 1,
     };
 
@@ -46,13 +253,59 @@ fn on_add_terminal_display(mut world: DeferredWorld, entity: Entity, _id: Compon
     image.resize(size);
     let image_handle = asset_server.add(image);
 
-    let headless_render_source = HeadlessRenderSource::new(&asset_server, image_handle.clone());
-    let post_process_settings = DitherPostProcessSettings::new(dither_level, &asset_server);
-    world
-        .commands()
+    let mask_handle = world
         .entity(entity)
-        .insert((headless_render_source, post_process_settings));
-    if let Some(mut camera) =  world.entity_mut(entity).get_mut::<Camera>() {
+        .contains::<GpuBrailleEncoding>()
+        .then(|| {
+            let mask_size = encoding::braille_mask_size(UVec2::new(size.width, size.height));
+            let mut mask = Image {
+                texture_descriptor: TextureDescriptor {
+                    label: None,
+                    size,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::Rgba8Uint,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+                    view_formats: &[],
+                },
+                ..default()
+            };
+            mask.resize(Extent3d {
+                width: mask_size.x,
+                height: mask_size.y,
+                depth_or_array_layers: 1,
+            });
+            asset_server.add(mask)
+        });
+
+    let headless_render_source = HeadlessRenderSource::new(
+        asset_server,
+        mask_handle.clone().unwrap_or_else(|| image_handle.clone()),
+    );
+    let post_process_settings = DitherPostProcessSettings::new(dither_level, asset_server);
+    let destination = HeadlessRenderDestination(Arc::new(Mutex::new(empty_image())));
+    let output = TerminalDisplayOutput {
+        entity,
+        destination: destination.clone(),
+        frames: default(),
+        viewport: default(),
+        render_target: image_handle.clone(),
+        mask_target: mask_handle.clone(),
+    };
+    world.commands().entity(entity).insert((
+        headless_render_source,
+        destination,
+        output,
+        post_process_settings,
+    ));
+    if let Some(mask_handle) = mask_handle {
+        world
+            .commands()
+            .entity(entity)
+            .insert(BrailleMaskTarget(mask_handle));
+    }
+    if let Some(mut camera) = world.entity_mut(entity).get_mut::<Camera>() {
         camera.target = image_handle.into();
     } else {
         world.commands().entity(entity).insert(Camera {
@@ -61,3 +314,92 @@ fn on_add_terminal_display(mut world: DeferredWorld, entity: Entity, _id: Compon
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bevy::{
+        prelude::*,
+        render::{
+            render_asset::RenderAssetUsages,
+            render_resource::{Extent3d, TextureDimension, TextureFormat},
+        },
+    };
+    use bevy_headless_render::components::HeadlessRenderDestination;
+
+    use super::{empty_image, DisplayFrames};
+
+    fn frame(width: u32, height: u32, data: &[u8]) -> Image {
+        Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data.to_vec(),
+            TextureFormat::R8Unorm,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    #[test]
+    fn only_changed_frames_are_received() {
+        let destination = HeadlessRenderDestination(Arc::new(Mutex::new(empty_image())));
+        let mut frames = DisplayFrames::default();
+        let render = |image: Image| {
+            *destination
+                .0
+                .lock()
+                .expect("Failed to get lock on destination") = image;
+        };
+        let consumed = || {
+            destination
+                .0
+                .lock()
+                .expect("Failed to get lock on destination")
+                .data
+                .is_empty()
+        };
+
+        // Nothing has been rendered yet
+        frames.receive(&destination, false);
+        assert_eq!(frames.sequence, 0);
+        assert!(frames.latest.is_none());
+
+        render(frame(2, 1, &[0, 255]));
+        frames.receive(&destination, false);
+        assert_eq!(frames.sequence, 1);
+        assert!(consumed());
+
+        // Frames are skipped while the destination is being written to
+        render(frame(2, 1, &[255, 0]));
+        {
+            let _writing = destination
+                .0
+                .lock()
+                .expect("Failed to get lock on destination");
+            frames.receive(&destination, false);
+        }
+        assert_eq!(frames.sequence, 1);
+        assert!(!consumed());
+        frames.receive(&destination, false);
+        assert_eq!(frames.sequence, 2);
+
+        // Repeated frames are consumed, but do not count as new frames
+        render(frame(2, 1, &[255, 0]));
+        frames.receive(&destination, false);
+        assert_eq!(frames.sequence, 2);
+        assert!(consumed());
+
+        // The same data at another size is a new frame
+        render(frame(1, 2, &[255, 0]));
+        frames.receive(&destination, false);
+        assert_eq!(frames.sequence, 3);
+        assert_eq!(
+            frames.latest.as_ref().map(Image::size),
+            Some(UVec2::new(1, 2))
+        );
+    }
+}
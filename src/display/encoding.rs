@@ -0,0 +1,405 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+    tasks::{ComputeTaskPool, TaskPool},
+};
+use ratatui::{
+    buffer::{Buffer, Cell},
+    layout::Rect,
+    style::Color,
+};
+
+const BRAILLE_CODE_MIN: u32 = 0x2800;
+
+/// 0 3
+/// 1 4
+/// 2 5
+/// 6 7
+const BRAILLE_DOT_BIT_POSITIONS: [u8; 8] = [0, 1, 2, 6, 3, 4, 5, 7];
+
+/// Braille dot bits for each row of a 2x4 block, indexed by `left | right << 1`
+const BRAILLE_ROW_MASKS: [[u8; 4]; 4] = {
+    let mut masks = [[0; 4]; 4];
+    let mut row = 0;
+    while row < 4 {
+        let left = 1 << BRAILLE_DOT_BIT_POSITIONS[row];
+        let right = 1 << BRAILLE_DOT_BIT_POSITIONS[row + 4];
+        masks[row] = [0, left, right, left | right];
+        row += 1;
+    }
+    masks
+};
+
+/// Braille character for each dot mask
+const BRAILLE_CHARS: [char; 256] = {
+    let mut chars = ['\0'; 256];
+    let mut mask = 0;
+    while mask < 256 {
+        chars[mask] = match char::from_u32(BRAILLE_CODE_MIN + mask as u32) {
+            Some(character) => character,
+            None => panic!("Error converting character!"),
+        };
+        mask += 1;
+    }
+    chars
+};
+
+/// Minimum number of cells in an area before encoding is split across the compute task pool
+const PARALLEL_CELL_THRESHOLD: usize = 4096;
+
+/// Highest level of Bayer matrix which images are dithered with, beyond which the number of
+/// cells in the matrix overflows
+pub const MAX_DITHER_LEVEL: u32 = 14;
+
+/// Characters used by the ASCII encoder, ordered from darkest to brightest
+const ASCII_RAMP: &[u8] = b" .:-=+*#%@";
+
+/// Method used to encode a grayscale image as terminal characters
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoder {
+    /// Braille characters, each covering a 2x4 block of pixels. Only fully white pixels are on,
+    /// so this works best with dithered images.
+    #[default]
+    Braille,
+    /// Upper half block characters, each covering a 1x2 block of pixels using the foreground and
+    /// background colours.
+    HalfBlock,
+    /// ASCII characters chosen by brightness, each covering a 1x2 block of pixels.
+    Ascii,
+}
+
+impl Encoder {
+    /// Number of pixels covered by a single character cell
+    pub fn cell_size(&self) -> UVec2 {
+        match self {
+            Encoder::Braille => UVec2::new(2, 4),
+            Encoder::HalfBlock | Encoder::Ascii => UVec2::new(1, 2),
+        }
+    }
+
+    /// Encodes an R8Unorm image into the given area of a buffer, starting from its top-left
+    /// pixel. Cells outside of the image are left untouched. Returns the area of the cells which
+    /// were written.
+    ///
+    /// Large areas are split into bands of rows which are encoded in parallel on the
+    /// [`ComputeTaskPool`], writing directly into the buffer's cells.
+    pub fn encode(&self, image: &Image, area: Rect, buffer: &mut Buffer) -> Rect {
+        let pool = ComputeTaskPool::try_get().map(|pool| &**pool);
+        self.encode_on(image, area, buffer, pool)
+    }
+
+    /// Encodes an image as [`Encoder::encode`] does, in parallel on the given pool if there is
+    /// one with several threads and the area is large enough
+    fn encode_on(
+        &self,
+        image: &Image,
+        area: Rect,
+        buffer: &mut Buffer,
+        pool: Option<&TaskPool>,
+    ) -> Rect {
+        let area = area.intersection(buffer.area);
+        let cell_size = self.cell_size();
+        let rows = (area.height as u32).min(image.height().div_ceil(cell_size.y)) as usize;
+        let columns = (area.width as u32).min(image.width().div_ceil(cell_size.x)) as usize;
+        let written = Rect::new(area.x, area.y, columns as u16, rows as u16);
+        if rows == 0 || columns == 0 {
+            return written;
+        }
+
+        // Each chunk of this slice starts at the left edge of the area on one line of the buffer
+        let buffer_width = buffer.area.width as usize;
+        let first = buffer.index_of(area.x, area.y);
+        let content = &mut buffer.content[first..];
+
+        let pool =
+            pool.filter(|pool| pool.thread_num() > 1 && rows * columns >= PARALLEL_CELL_THRESHOLD);
+        let Some(pool) = pool else {
+            self.encode_rows(image, 0, content, buffer_width, rows, columns);
+            return written;
+        };
+
+        let rows_per_task = rows.div_ceil(pool.thread_num());
+        pool.scope(|scope| {
+            for (task, band) in content
+                .chunks_mut(buffer_width * rows_per_task)
+                .take(rows.div_ceil(rows_per_task))
+                .enumerate()
+            {
+                let first_row = task * rows_per_task;
+                let band_rows = rows_per_task.min(rows - first_row);
+                scope.spawn(async move {
+                    self.encode_rows(image, first_row, band, buffer_width, band_rows, columns);
+                });
+            }
+        });
+        written
+    }
+
+    /// Encodes a band of consecutive character rows, beginning at `first_row`, into `content`
+    fn encode_rows(
+        &self,
+        image: &Image,
+        first_row: usize,
+        content: &mut [Cell],
+        buffer_width: usize,
+        rows: usize,
+        columns: usize,
+    ) {
+        for (row, line) in content.chunks_mut(buffer_width).take(rows).enumerate() {
+            let cells = &mut line[..columns];
+            match self {
+                Encoder::Braille => encode_braille_row(image, first_row + row, cells),
+                Encoder::HalfBlock => encode_half_block_row(image, first_row + row, cells),
+                Encoder::Ascii => encode_ascii_row(image, first_row + row, cells),
+            }
+        }
+    }
+}
+
+/// Returns the given row of pixels of an image, or `None` if it lies outside of the image
+fn pixel_row(image: &Image, y: usize) -> Option<&[u8]> {
+    let width = image.width() as usize;
+    image.data.get(y * width..(y + 1) * width)
+}
+
+/// Encodes one row of braille characters, each covering a 2x4 block of pixels
+fn encode_braille_row(image: &Image, row: usize, cells: &mut [Cell]) {
+    let pixel_rows: [Option<&[u8]>; 4] =
+        std::array::from_fn(|offset| pixel_row(image, row * 4 + offset));
+    for (column, cell) in cells.iter_mut().enumerate() {
+        let x = column * 2;
+        let mut mask: u8 = 0;
+        for (offset, pixels) in pixel_rows.iter().enumerate() {
+            let Some(pixels) = pixels else {
+                break;
+            };
+            let left = (pixels[x] == 0xFF) as usize;
+            let right = pixels.get(x + 1).is_some_and(|&value| value == 0xFF) as usize;
+            mask |= BRAILLE_ROW_MASKS[offset][left | (right << 1)];
+        }
+        cell.set_char(BRAILLE_CHARS[mask as usize])
+            .set_fg(Color::White);
+    }
+}
+
+/// Encodes one row of half block characters, each covering a 1x2 block of pixels
+fn encode_half_block_row(image: &Image, row: usize, cells: &mut [Cell]) {
+    let top = pixel_row(image, row * 2);
+    let bottom = pixel_row(image, row * 2 + 1);
+    for (x, cell) in cells.iter_mut().enumerate() {
+        let top = top.map_or(0, |pixels| pixels[x]);
+        let bottom = bottom.map_or(0, |pixels| pixels[x]);
+        cell.set_char('▀')
+            .set_fg(Color::Rgb(top, top, top))
+            .set_bg(Color::Rgb(bottom, bottom, bottom));
+    }
+}
+
+/// Encodes one row of ASCII characters, each covering a 1x2 block of pixels
+fn encode_ascii_row(image: &Image, row: usize, cells: &mut [Cell]) {
+    let top = pixel_row(image, row * 2);
+    let bottom = pixel_row(image, row * 2 + 1);
+    for (x, cell) in cells.iter_mut().enumerate() {
+        let top = top.map_or(0, |pixels| pixels[x]) as usize;
+        let bottom = bottom.map_or(0, |pixels| pixels[x]) as usize;
+        let brightness = (top + bottom) / 2;
+        let index = brightness * (ASCII_RAMP.len() - 1) / 0xFF;
+        cell.set_char(ASCII_RAMP[index] as char)
+            .set_fg(Color::White);
+    }
+}
+
+/// Size of the packed mask image produced by the GPU encoding pass for a render target of the
+/// given size. Each texel holds the braille masks of four horizontally adjacent cells.
+pub(crate) fn braille_mask_size(render_size: UVec2) -> UVec2 {
+    UVec2::new(
+        render_size.x.div_ceil(2).div_ceil(4),
+        render_size.y.div_ceil(4),
+    )
+    .max(UVec2::ONE)
+}
+
+/// Draws braille characters from an image of packed cell masks, as produced by the GPU encoding
+/// pass, into the given area of a buffer. Returns the area of the cells which were written.
+pub(crate) fn decode_braille_masks(masks: &Image, area: Rect, buffer: &mut Buffer) -> Rect {
+    let area = area.intersection(buffer.area);
+    let stride = masks.width() as usize * 4;
+    let written = Rect::new(
+        area.x,
+        area.y,
+        area.width.min(stride as u16),
+        area.height.min(masks.height() as u16),
+    );
+    if stride == 0 {
+        return written;
+    }
+    for (row, line) in masks
+        .data
+        .chunks_exact(stride)
+        .take(area.height as usize)
+        .enumerate()
+    {
+        for (column, mask) in line.iter().take(area.width as usize).enumerate() {
+            buffer[(area.x + column as u16, area.y + row as u16)]
+                .set_char(BRAILLE_CHARS[*mask as usize])
+                .set_fg(Color::White);
+        }
+    }
+    written
+}
+
+/// Converts the given image to R8Unorm format in place if it is not already in that format
+pub(crate) fn ensure_r8unorm(image: &mut Image) {
+    if image.texture_descriptor.format != TextureFormat::R8Unorm {
+        warn_once!("Extracted framebuffer texture is not R8Unorm format. Will attempt conversion, but consider changing your render texture's format.");
+        info_once!("{:?}", image);
+        match image.convert(TextureFormat::R8Unorm) {
+            Some(img) => *image = img,
+            None => error_once!(
+                "Could not convert to R8Unorm texture format. Unexpected output may occur."
+            ),
+        };
+    }
+}
+
+/// Scales an R8Unorm image to the given size using nearest-neighbour sampling
+pub(crate) fn scale(image: &Image, size: UVec2) -> Image {
+    let width = image.width().max(1);
+    let height = image.height().max(1);
+    let mut data = Vec::with_capacity((size.x * size.y) as usize);
+    for y in 0..size.y {
+        let source_y = y * height / size.y;
+        for x in 0..size.x {
+            let source_x = x * width / size.x;
+            data.push(
+                image
+                    .data
+                    .get((source_y * width + source_x) as usize)
+                    .copied()
+                    .unwrap_or_default(),
+            );
+        }
+    }
+    Image::new(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::R8Unorm,
+        RenderAssetUsages::MAIN_WORLD,
+    )
+}
+
+/// Dithers an R8Unorm image to pure black and white in place, using a Bayer matrix of the given
+/// level, clamped to [`MAX_DITHER_LEVEL`]. Matches the output of the dither post-process applied
+/// to terminal displays.
+pub(crate) fn dither(image: &mut Image, level: u32) {
+    let power = level.min(MAX_DITHER_LEVEL) + 1;
+    let map_size: u32 = 1 << power;
+    let width = image.width();
+    for (index, value) in image.data.iter_mut().enumerate() {
+        let x = index as u32 % width;
+        let y = index as u32 / width;
+        let threshold = bayer_threshold(x % map_size, y % map_size, power, map_size);
+        *value = if *value > threshold { 0xFF } else { 0x00 };
+    }
+}
+
+/// Computes the threshold of a Bayer matrix cell, scaled to the range of a u8
+fn bayer_threshold(col: u32, row: u32, power: u32, map_size: u32) -> u8 {
+    let a = row ^ col;
+    // Interleave bits of `a` with bits of y coordinate in reverse order
+    let mut result: u64 = 0;
+    let mut bit = 0;
+    let mut mask = power as i32 - 1;
+    while bit < 2 * power {
+        result |= (((col >> mask) & 1) << bit) as u64;
+        bit += 1;
+        result |= (((a >> mask) & 1) << bit) as u64;
+        bit += 1;
+        mask -= 1;
+    }
+    ((result as f32 / map_size.pow(2) as f32) * 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        prelude::*,
+        render::{
+            render_asset::RenderAssetUsages,
+            render_resource::{Extent3d, TextureDimension, TextureFormat},
+        },
+        tasks::TaskPoolBuilder,
+    };
+    use ratatui::{buffer::Buffer, layout::Rect};
+
+    use super::{dither, Encoder, MAX_DITHER_LEVEL, PARALLEL_CELL_THRESHOLD};
+
+    /// R8Unorm image of the given size with the given pixels
+    fn grey_image(width: u32, height: u32, data: Vec<u8>) -> Image {
+        Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::R8Unorm,
+            RenderAssetUsages::MAIN_WORLD,
+        )
+    }
+
+    #[test]
+    fn braille_dots_are_only_on_for_white_pixels() {
+        let image = grey_image(2, 4, vec![0xFF, 0xFE, 0x80, 0x00, 0x00, 0x00, 0x00, 0xFF]);
+        let mut buffer = Buffer::empty(Rect::new(0, 0, 1, 1));
+        Encoder::Braille.encode(&image, buffer.area, &mut buffer);
+        // Dots 1 and 8
+        assert_eq!(buffer[(0, 0)].symbol(), "⢁");
+    }
+
+    #[test]
+    fn dither_level_is_clamped() {
+        let data = (0..=255).collect::<Vec<u8>>();
+        let mut clamped = grey_image(16, 16, data.clone());
+        dither(&mut clamped, u32::MAX);
+        let mut highest = grey_image(16, 16, data);
+        dither(&mut highest, MAX_DITHER_LEVEL);
+        assert_eq!(clamped.data, highest.data);
+    }
+
+    #[test]
+    fn parallel_encoding_matches_serial_encoding() {
+        let pool = TaskPoolBuilder::new().num_threads(3).build();
+        assert_eq!(pool.thread_num(), 3);
+        // The rows are not a multiple of the rows encoded by each task, and the area is inset in
+        // the buffer, so bands start partway through lines of the buffer
+        let buffer_area = Rect::new(0, 0, 110, 70);
+        let area = Rect::new(3, 2, 101, 67);
+        assert!(area.area() as usize >= PARALLEL_CELL_THRESHOLD);
+        for encoder in [Encoder::Braille, Encoder::HalfBlock, Encoder::Ascii] {
+            let size = UVec2::new(area.width.into(), area.height.into()) * encoder.cell_size();
+            // Odd sizes leave the last row and column of cells partly outside of the image
+            let (width, height) = (size.x - 1, size.y - 1);
+            let data = (0..width * height)
+                .map(|index| (index.wrapping_mul(2_654_435_761) >> 24) as u8)
+                .collect();
+            let image = grey_image(width, height, data);
+
+            let mut serial = Buffer::empty(buffer_area);
+            let serial_written = encoder.encode_on(&image, area, &mut serial, None);
+            let mut parallel = Buffer::empty(buffer_area);
+            let parallel_written = encoder.encode_on(&image, area, &mut parallel, Some(&pool));
+            assert_eq!(parallel_written, serial_written);
+            assert_eq!(parallel, serial, "{encoder:?} encodings differ");
+        }
+    }
+}
@@ -6,3 +6,19 @@ pub mod resources;
 
 /// Systems for this module
 pub(crate) mod systems;
+
+/// Types and functions for encoding images as terminal characters
+pub mod encoding;
+
+/// Render graph nodes for this module
+pub(crate) mod nodes;
+
+/// Ratatui backends frames can be drawn to instead of a terminal
+pub mod backend;
+
+/// Background thread which writes frames to the terminal
+pub(crate) mod output;
+
+/// Functions for querying terminal capabilities. Queries are answered through input, so they must
+/// be made in raw mode, before anything else starts reading input.
+pub(crate) mod query;
@@ -0,0 +1,159 @@
+use bevy::{
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
+        render_resource::{BindGroupEntries, ComputePassDescriptor, PipelineCache},
+        renderer::RenderContext,
+        texture::GpuImage,
+        view::ViewTarget,
+    },
+};
+
+use super::{components::BrailleMaskTarget, resources::BrailleMaskPipeline};
+
+/// Label for the braille mask compute node
+#[derive(RenderLabel, Clone, Eq, PartialEq, Hash, Debug)]
+pub(crate) struct BrailleMaskLabel;
+
+/// Render node which packs the dithered output of a view into braille masks. Only added to the
+/// `Core3d` graph, since the dither post-process it follows is only applied to 3D cameras.
+#[derive(Default)]
+pub(crate) struct BrailleMaskNode;
+
+impl ViewNode for BrailleMaskNode {
+    type ViewQuery = (&'static ViewTarget, &'static BrailleMaskTarget);
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (view_target, mask_target): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let mask_pipeline = world.resource::<BrailleMaskPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(mask_pipeline.pipeline_id) else {
+            warn_once!("Failed to get braille mask pipeline from cache, skipping...");
+            return Ok(());
+        };
+
+        let Some(mask_image) = world
+            .resource::<RenderAssets<GpuImage>>()
+            .get(mask_target.0.id())
+        else {
+            warn_once!("Failed to get braille mask target, skipping...");
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            "braille_mask_bind_group",
+            &mask_pipeline.layout,
+            &BindGroupEntries::sequential((
+                view_target.main_texture_view(),
+                &mask_image.texture_view,
+            )),
+        );
+
+        let mut compute_pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("braille_mask_compute_pass"),
+                    timestamp_writes: None,
+                });
+        compute_pass.set_pipeline(pipeline);
+        compute_pass.set_bind_group(0, &bind_group, &[]);
+        compute_pass.dispatch_workgroups(
+            mask_image.size.x.div_ceil(8),
+            mask_image.size.y.div_ceil(8),
+            1,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        core_pipeline::tonemapping::Tonemapping, log::LogPlugin, prelude::*,
+        render::camera::ClearColorConfig, window::ExitCondition,
+    };
+    use ratatui::{
+        buffer::Buffer,
+        layout::{Rect, Size},
+    };
+
+    use crate::{
+        display::components::{GpuBrailleEncoding, TerminalDisplay, TerminalDisplayOutput},
+        testing::snapshot::SnapshotApp,
+        TerminalDisplayPlugin, TerminalTarget,
+    };
+
+    /// Greys and dither levels of the displays compared, chosen so that their dither patterns
+    /// light the dots of each braille cell in different combinations
+    const DISPLAYS: [(f32, u32); 5] = [(0.3, 0), (0.6, 0), (0.85, 0), (0.55, 1), (0.45, 3)];
+
+    /// Spawns a display which clears to the given grey
+    fn spawn_display(app: &mut SnapshotApp, grey: f32, level: u32, gpu_encoding: bool) -> Entity {
+        let mut display = app.app_mut().world_mut().spawn((
+            Camera3d::default(),
+            Camera {
+                clear_color: ClearColorConfig::Custom(Color::linear_rgb(grey, grey, grey)),
+                ..default()
+            },
+            Tonemapping::None,
+        ));
+        if gpu_encoding {
+            display.insert(GpuBrailleEncoding);
+        }
+        display.insert(TerminalDisplay(level)).id()
+    }
+
+    fn draw(app: &SnapshotApp, display: Entity, area: Rect) -> Buffer {
+        let output = app
+            .app()
+            .world()
+            .get::<TerminalDisplayOutput>(display)
+            .expect("Display has no output");
+        assert!(output.frame_sequence() > 0, "No frame was read back");
+        let mut buffer = Buffer::empty(area);
+        output.draw(area, &mut buffer);
+        buffer
+    }
+
+    #[test]
+    #[ignore = "needs a GPU adapter, such as a software renderer like lavapipe"]
+    fn gpu_encoding_matches_cpu_encoding() {
+        let area = Rect::new(0, 0, 12, 6);
+        let mut app = SnapshotApp::with_plugins((
+            DefaultPlugins
+                .build()
+                // The plugin sets up logging itself
+                .disable::<LogPlugin>()
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    ..default()
+                }),
+            TerminalDisplayPlugin {
+                target: TerminalTarget::Headless(Size::new(area.width, area.height)),
+                log_path: std::env::temp_dir().join("bevy_terminal_display_snapshot.log"),
+                ..default()
+            },
+        ));
+        let displays = DISPLAYS.map(|(grey, level)| {
+            (
+                spawn_display(&mut app, grey, level, false),
+                spawn_display(&mut app, grey, level, true),
+            )
+        });
+        app.step(20);
+
+        for (cpu, gpu) in displays {
+            let expected = draw(&app, cpu, area);
+            assert_eq!(draw(&app, gpu, area), expected);
+        }
+    }
+}
@@ -0,0 +1,667 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread::JoinHandle,
+    time::Instant,
+};
+
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+use crossterm::{
+    terminal::{BeginSynchronizedUpdate, EndSynchronizedUpdate},
+    QueueableCommand,
+};
+use ratatui::{
+    backend::{Backend, ClearType, CrosstermBackend, WindowSize},
+    buffer::{Buffer, Cell},
+    layout::{Position, Rect, Size},
+    style::Color,
+};
+
+use crate::tty::{BoxedWriter, Tty};
+
+use super::{backend::BoxedBackend, resources::OutputStats};
+
+/// Ratatui backend which does not write anything itself, and only tracks the terminal's size and
+/// cursor state. Completed frames are handed to an [`OutputThread`] instead.
+#[derive(Default)]
+pub(crate) struct FrameBackend {
+    cursor_visible: bool,
+    cursor_position: Position,
+    device: Option<Arc<Tty>>,
+    /// Size reported when there is no real terminal
+    virtual_size: Option<Size>,
+}
+
+impl FrameBackend {
+    /// Constructs a new backend, reporting the size of the given terminal device, or of the
+    /// controlling terminal if there is none
+    pub(crate) fn new(device: Option<Arc<Tty>>) -> Self {
+        Self {
+            device,
+            ..default()
+        }
+    }
+
+    /// Constructs a new backend for a virtual terminal of the given size
+    pub(crate) fn headless(size: Size) -> Self {
+        Self {
+            virtual_size: Some(size),
+            ..default()
+        }
+    }
+
+    /// Changes the size reported for a virtual terminal
+    pub(crate) fn set_virtual_size(&mut self, size: Size) {
+        self.virtual_size = Some(size);
+    }
+
+    /// Position of the cursor if it should be shown, or `None` if it is hidden
+    pub(crate) fn cursor(&self) -> Option<Position> {
+        self.cursor_visible.then_some(self.cursor_position)
+    }
+}
+
+impl Backend for FrameBackend {
+    fn draw<'a, I>(&mut self, _content: I) -> io::Result<()>
+    where
+        I: Iterator<Item = (u16, u16, &'a Cell)>,
+    {
+        Ok(())
+    }
+
+    fn hide_cursor(&mut self) -> io::Result<()> {
+        self.cursor_visible = false;
+        Ok(())
+    }
+
+    fn show_cursor(&mut self) -> io::Result<()> {
+        self.cursor_visible = true;
+        Ok(())
+    }
+
+    fn get_cursor_position(&mut self) -> io::Result<Position> {
+        Ok(self.cursor_position)
+    }
+
+    fn set_cursor_position<P: Into<Position>>(&mut self, position: P) -> io::Result<()> {
+        self.cursor_position = position.into();
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn clear_region(&mut self, _clear_type: ClearType) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<Size> {
+        if let Some(size) = self.virtual_size {
+            return Ok(size);
+        }
+        let (width, height) = match &self.device {
+            Some(device) => device.size()?,
+            None => crossterm::terminal::size()?,
+        };
+        Ok(Size { width, height })
+    }
+
+    fn window_size(&mut self) -> io::Result<WindowSize> {
+        if let Some(size) = self.virtual_size {
+            return Ok(WindowSize {
+                columns_rows: size,
+                pixels: Size::ZERO,
+            });
+        }
+        let crossterm::terminal::WindowSize {
+            columns,
+            rows,
+            width,
+            height,
+        } = crossterm::terminal::window_size()?;
+        Ok(WindowSize {
+            columns_rows: Size {
+                width: columns,
+                height: rows,
+            },
+            pixels: Size { width, height },
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Completed frame waiting to be written to the terminal
+struct PendingFrame {
+    buffer: Buffer,
+    cursor: Option<Position>,
+}
+
+#[derive(Default)]
+struct OutputState {
+    pending: Option<PendingFrame>,
+    /// Whether a frame is currently being written
+    writing: bool,
+    stats: OutputStats,
+    shutdown: bool,
+    /// Whether the output thread has stopped, including by panicking
+    stopped: bool,
+}
+
+/// Marks the output thread as stopped once dropped, including when the thread panics, so that
+/// nothing waits for it forever
+struct StoppedGuard<'a>(&'a (Mutex<OutputState>, Condvar));
+
+impl Drop for StoppedGuard<'_> {
+    fn drop(&mut self) {
+        let (state, condvar) = self.0;
+        state.lock().unwrap_or_else(PoisonError::into_inner).stopped = true;
+        condvar.notify_all();
+    }
+}
+
+/// Writer which counts the number of bytes written through it
+struct CountingWriter<W> {
+    inner: W,
+    bytes_written: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes_written
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How the output thread writes frames
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OutputOptions {
+    /// Whether each frame is wrapped in synchronized update sequences
+    pub(crate) synchronized: bool,
+    /// Whether the whole screen is cleared when the size of the frames changes. Otherwise only
+    /// the frame's area is redrawn.
+    pub(crate) clear_screen: bool,
+    /// Whether the cursor is hidden while frames do not place it
+    pub(crate) hide_cursor: bool,
+}
+
+/// Background thread which writes completed frames to the terminal. If a frame is submitted while
+/// the previous one is still waiting to be written, the newer frame replaces it, so slow output
+/// never stalls the app.
+pub(crate) struct OutputThread {
+    state: Arc<(Mutex<OutputState>, Condvar)>,
+    recycled: Receiver<Buffer>,
+    handle: Option<JoinHandle<BoxedWriter>>,
+}
+
+impl OutputThread {
+    /// Spawns a new output thread writing to the given writer
+    pub(crate) fn spawn(writer: BoxedWriter, options: OutputOptions) -> Self {
+        let state = Arc::new((Mutex::new(OutputState::default()), Condvar::new()));
+        let (recycle_sender, recycled) = crossbeam_channel::bounded(2);
+        let handle = std::thread::Builder::new()
+            .name("terminal output".into())
+            .spawn({
+                let state = state.clone();
+                move || write_frames(writer, options, state, recycle_sender)
+            })
+            .expect("Failed to spawn terminal output thread");
+        Self {
+            state,
+            recycled,
+            handle: Some(handle),
+        }
+    }
+
+    /// Copies a completed frame, reusing the allocation of a frame which has already been written
+    /// if there is one
+    pub(crate) fn copy_frame(&self, frame: &Buffer) -> Buffer {
+        let mut buffer = self.recycled.try_recv().unwrap_or_default();
+        buffer.area = frame.area;
+        buffer.content.clone_from(&frame.content);
+        buffer
+    }
+
+    /// Queues a frame to be written, replacing any frame which has not been written yet
+    pub(crate) fn submit(&self, buffer: Buffer, cursor: Option<Position>) {
+        let (state, condvar) = &*self.state;
+        let mut state = state.lock().expect("Failed to get lock on output state");
+        if state
+            .pending
+            .replace(PendingFrame { buffer, cursor })
+            .is_some()
+        {
+            state.stats.frames_dropped += 1;
+        }
+        condvar.notify_all();
+    }
+
+    /// Blocks until every submitted frame has been written, or the output thread has stopped
+    pub(crate) fn wait_idle(&self) {
+        let (state, condvar) = &*self.state;
+        // The lock is poisoned if the output thread panicked while holding it
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        while (state.pending.is_some() || state.writing) && !state.shutdown && !state.stopped {
+            state = condvar.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Statistics about the frames written so far
+    pub(crate) fn stats(&self) -> OutputStats {
+        self.state
+            .0
+            .lock()
+            .expect("Failed to get lock on output state")
+            .stats
+    }
+
+    /// Stops the output thread once it has written the frame waiting to be written, if there is
+    /// one, returning the writer it was writing to
+    pub(crate) fn shutdown(&mut self) -> Option<BoxedWriter> {
+        let handle = self.handle.take()?;
+        {
+            let (state, condvar) = &*self.state;
+            if let Ok(mut state) = state.lock() {
+                state.shutdown = true;
+            }
+            condvar.notify_all();
+        }
+        handle.join().ok()
+    }
+}
+
+impl Drop for OutputThread {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// Destination of the frames drawn by a terminal
+pub(crate) enum FrameOutput {
+    /// Frames are written to a terminal by a background thread
+    Thread(OutputThread),
+    /// Frames are drawn to a backend as soon as they are submitted
+    Backend(BackendOutput),
+}
+
+impl FrameOutput {
+    /// Copies a completed frame, reusing the allocation of a frame which has already been written
+    /// if there is one
+    pub(crate) fn copy_frame(&self, frame: &Buffer) -> Buffer {
+        match self {
+            FrameOutput::Thread(thread) => thread.copy_frame(frame),
+            FrameOutput::Backend(_) => frame.clone(),
+        }
+    }
+
+    /// Hands a frame to the output to be written
+    pub(crate) fn submit(&mut self, buffer: Buffer, cursor: Option<Position>) {
+        match self {
+            FrameOutput::Thread(thread) => thread.submit(buffer, cursor),
+            FrameOutput::Backend(output) => output.write(PendingFrame { buffer, cursor }),
+        }
+    }
+
+    /// Statistics about the frames written so far
+    pub(crate) fn stats(&self) -> OutputStats {
+        match self {
+            FrameOutput::Thread(thread) => thread.stats(),
+            FrameOutput::Backend(output) => output.stats,
+        }
+    }
+
+    /// Blocks until every submitted frame has been written
+    pub(crate) fn wait_idle(&self) {
+        if let FrameOutput::Thread(thread) = self {
+            thread.wait_idle();
+        }
+    }
+
+    /// Stops writing frames, returning the writer frames were written to, if there is one
+    pub(crate) fn shutdown(&mut self) -> Option<BoxedWriter> {
+        match self {
+            FrameOutput::Thread(thread) => thread.shutdown(),
+            FrameOutput::Backend(_) => None,
+        }
+    }
+}
+
+/// Draws frames directly to a backend on the calling thread, so they can be inspected as soon as
+/// they have been drawn
+pub(crate) struct BackendOutput {
+    pub(crate) backend: BoxedBackend,
+    /// Last frame drawn to the backend
+    pub(crate) last: Buffer,
+    options: OutputOptions,
+    cursor_visible: bool,
+    stats: OutputStats,
+}
+
+impl BackendOutput {
+    pub(crate) fn new(backend: BoxedBackend, options: OutputOptions) -> Self {
+        Self {
+            backend,
+            last: Buffer::empty(Rect::ZERO),
+            options,
+            cursor_visible: true,
+            stats: default(),
+        }
+    }
+
+    fn write(&mut self, mut frame: PendingFrame) {
+        let start = Instant::now();
+        let result = draw_frame(
+            &mut self.backend,
+            &self.last,
+            &frame,
+            self.options,
+            &mut self.cursor_visible,
+        )
+        .and_then(|_| self.backend.flush());
+        self.stats.frames_written += 1;
+        self.stats.write_time += start.elapsed();
+        if let Err(error) = result {
+            error!("Failed to draw frame to backend: {error}");
+            // Force a full redraw, since the backend's contents are unknown
+            self.last = Buffer::empty(Rect::ZERO);
+            return;
+        }
+        std::mem::swap(&mut self.last, &mut frame.buffer);
+    }
+}
+
+/// Writes frames until the output thread is shut down and no frame is waiting, then returns the
+/// writer
+fn write_frames<W: Write>(
+    mut writer: W,
+    options: OutputOptions,
+    state: Arc<(Mutex<OutputState>, Condvar)>,
+    recycle_sender: Sender<Buffer>,
+) -> W {
+    let _stopped = StoppedGuard(&state);
+    let bytes_written = Arc::new(AtomicU64::new(0));
+    let mut backend = CrosstermBackend::new(CountingWriter {
+        inner: &mut writer,
+        bytes_written: bytes_written.clone(),
+    });
+    let mut last = Buffer::empty(Rect::ZERO);
+    let mut cursor_visible = true;
+    loop {
+        let frame = {
+            let (state, condvar) = &*state;
+            let mut state = state.lock().expect("Failed to get lock on output state");
+            while state.pending.is_none() && !state.shutdown {
+                state = condvar
+                    .wait(state)
+                    .expect("Failed to get lock on output state");
+            }
+            // The last submitted frame is still written when shutting down, so that it is left on
+            // the screen
+            if state.shutdown && state.pending.is_none() {
+                break;
+            }
+            state.writing = true;
+            state.pending.take()
+        };
+        let Some(mut frame) = frame else {
+            continue;
+        };
+
+        let start = Instant::now();
+        let result = write_frame(&mut backend, &last, &frame, options, &mut cursor_visible);
+        {
+            let (state, condvar) = &*state;
+            let mut state = state.lock().expect("Failed to get lock on output state");
+            state.stats.frames_written += 1;
+            state.stats.bytes_written += bytes_written.swap(0, Ordering::Relaxed);
+            state.stats.write_time += start.elapsed();
+            state.writing = false;
+            condvar.notify_all();
+        }
+        if let Err(error) = result {
+            error!("Failed to write terminal frame: {error}");
+            // Force a full redraw, since the terminal's contents are unknown
+            last = Buffer::empty(Rect::ZERO);
+            continue;
+        }
+
+        std::mem::swap(&mut last, &mut frame.buffer);
+        let _ = recycle_sender.try_send(frame.buffer);
+    }
+    drop(backend);
+    writer
+}
+
+/// Whether any cell of a frame uses a true colour
+pub(crate) fn has_true_colour(buffer: &Buffer) -> bool {
+    buffer
+        .content
+        .iter()
+        .any(|cell| matches!(cell.fg, Color::Rgb(..)) || matches!(cell.bg, Color::Rgb(..)))
+}
+
+/// Replaces true colours in a frame with the closest colours of the 256 colour palette, which take
+/// fewer bytes to write
+pub(crate) fn reduce_colours(buffer: &mut Buffer) {
+    for cell in buffer.content.iter_mut() {
+        cell.fg = reduce_colour(cell.fg);
+        cell.bg = reduce_colour(cell.bg);
+    }
+}
+
+fn reduce_colour(colour: Color) -> Color {
+    let Color::Rgb(r, g, b) = colour else {
+        return colour;
+    };
+    if r == g && g == b {
+        // Grayscale ramp, from 8 to 238 in steps of 10
+        return match r {
+            0..4 => Color::Indexed(16),
+            247.. => Color::Indexed(231),
+            _ => Color::Indexed(232 + (r.saturating_sub(3) / 10).min(23)),
+        };
+    }
+    // Each channel is rounded to the nearest of the six levels of the colour cube
+    let level = |value: u8| ((value as u16 * 5 + 127) / 255) as u8;
+    Color::Indexed(16 + 36 * level(r) + 6 * level(g) + level(b))
+}
+
+/// Writes a frame to a terminal, wrapping it in synchronized update sequences if enabled
+fn write_frame<W: Write>(
+    backend: &mut CrosstermBackend<W>,
+    last: &Buffer,
+    frame: &PendingFrame,
+    options: OutputOptions,
+    cursor_visible: &mut bool,
+) -> io::Result<()> {
+    if options.synchronized {
+        backend.queue(BeginSynchronizedUpdate)?;
+    }
+    draw_frame(backend, last, frame, options, cursor_visible)?;
+    if options.synchronized {
+        backend.queue(EndSynchronizedUpdate)?;
+    }
+    Backend::flush(backend)
+}
+
+/// Draws the cells of a frame which differ from the last frame to a backend, and updates the
+/// cursor, without flushing
+fn draw_frame<B: Backend>(
+    backend: &mut B,
+    last: &Buffer,
+    frame: &PendingFrame,
+    options: OutputOptions,
+    cursor_visible: &mut bool,
+) -> io::Result<()> {
+    if last.area != frame.buffer.area && options.clear_screen {
+        backend.clear()?;
+        let blank = Buffer::empty(frame.buffer.area);
+        backend.draw(blank.diff(&frame.buffer).into_iter())?;
+    } else if last.area != frame.buffer.area {
+        // Overwrite every cell of the area, without touching the rest of the screen
+        let mut unknown = Buffer::empty(frame.buffer.area);
+        for cell in unknown.content.iter_mut() {
+            cell.set_symbol("\0");
+        }
+        backend.draw(unknown.diff(&frame.buffer).into_iter())?;
+    } else {
+        backend.draw(last.diff(&frame.buffer).into_iter())?;
+    }
+
+    match frame.cursor {
+        Some(position) => {
+            if !*cursor_visible {
+                backend.show_cursor()?;
+                *cursor_visible = true;
+            }
+            backend.set_cursor_position(position)?;
+        }
+        None => {
+            if *cursor_visible && options.hide_cursor {
+                backend.hide_cursor()?;
+                *cursor_visible = false;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::capture::OutputCapture;
+
+    #[test]
+    fn colours_round_to_nearest_level() {
+        let reduced = [
+            (Color::Rgb(255, 0, 128), Color::Indexed(16 + 36 * 5 + 3)),
+            (Color::Rgb(26, 25, 0), Color::Indexed(16 + 36)),
+            (Color::Rgb(230, 229, 1), Color::Indexed(16 + 36 * 5 + 6 * 4)),
+            (Color::Rgb(0, 0, 0), Color::Indexed(16)),
+            (Color::Rgb(128, 128, 128), Color::Indexed(244)),
+            (Color::Rgb(255, 255, 255), Color::Indexed(231)),
+            (Color::Red, Color::Red),
+        ];
+        for (colour, expected) in reduced {
+            assert_eq!(reduce_colour(colour), expected, "{colour:?}");
+        }
+    }
+
+    #[test]
+    fn shutdown_writes_pending_frame() {
+        let capture = OutputCapture::new(5, 1);
+        let mut thread = OutputThread::spawn(Box::new(capture.clone()), OPTIONS);
+        thread.submit(Buffer::with_lines(["first"]), None);
+        thread.submit(Buffer::with_lines(["last"]), None);
+        thread.shutdown();
+        let output = String::from_utf8_lossy(&capture.bytes()).into_owned();
+        assert!(
+            output.contains("last"),
+            "Last frame was not written: {output:?}"
+        );
+    }
+
+    const OPTIONS: OutputOptions = OutputOptions {
+        synchronized: false,
+        clear_screen: true,
+        hide_cursor: false,
+    };
+
+    /// Writer which blocks the first write until it is released
+    struct GatedWriter {
+        inner: OutputCapture,
+        gate: Option<(Sender<()>, Receiver<()>)>,
+    }
+
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some((started, release)) = self.gate.take() {
+                let _ = started.send(());
+                let _ = release.recv();
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Writer which panics when written to
+    struct PanickingWriter;
+
+    impl Write for PanickingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            panic!("Writer panicked");
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_waiting_to_be_written_are_replaced() {
+        let capture = OutputCapture::new(5, 1);
+        let (started_sender, started) = crossbeam_channel::bounded(1);
+        let (release, release_receiver) = crossbeam_channel::bounded(1);
+        let thread = OutputThread::spawn(
+            Box::new(GatedWriter {
+                inner: capture.clone(),
+                gate: Some((started_sender, release_receiver)),
+            }),
+            OPTIONS,
+        );
+        thread.submit(Buffer::with_lines(["one"]), None);
+        started.recv().expect("Output thread did not start writing");
+        // The first frame is being written, so the second waits and is replaced by the third
+        thread.submit(Buffer::with_lines(["two"]), None);
+        thread.submit(Buffer::with_lines(["three"]), None);
+        release.send(()).expect("Output thread stopped");
+        thread.wait_idle();
+
+        let stats = thread.stats();
+        assert_eq!(stats.frames_written, 2);
+        assert_eq!(stats.frames_dropped, 1);
+        let output = String::from_utf8_lossy(&capture.bytes()).into_owned();
+        assert!(
+            output.contains("one"),
+            "First frame was not written: {output:?}"
+        );
+        assert!(
+            !output.contains("two"),
+            "Replaced frame was written: {output:?}"
+        );
+        assert!(
+            output.contains("three"),
+            "Last frame was not written: {output:?}"
+        );
+    }
+
+    #[test]
+    fn waiting_for_panicked_output_thread_returns() {
+        let thread = OutputThread::spawn(Box::new(PanickingWriter), OPTIONS);
+        thread.submit(Buffer::with_lines(["frame"]), None);
+        let (done, finished) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || {
+            thread.wait_idle();
+            let _ = done.send(());
+        });
+        finished
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("Waiting for the output thread did not return");
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    io::{self, Write},
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use crate::tty::Tty;
+
+/// How long to wait for the terminal to answer a query before assuming it is unsupported
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Asks the terminal whether it supports synchronized output (DEC private mode 2026) using a
+/// DECRQM query. A primary device attributes query is sent afterwards, which every terminal
+/// answers, so that terminals which ignore DECRQM do not have to wait for the timeout. Any other
+/// input read while waiting for the responses is appended to `input`.
+pub(crate) fn supports_synchronized_output(
+    device: Option<&Tty>,
+    input: &mut Vec<u8>,
+) -> io::Result<bool> {
+    let response = query(device, b"\x1b[?2026$p\x1b[c", answered_device_attributes)?;
+    input.extend(without_sequences(&response, b"\x1b[?", |_, final_bytes| {
+        matches!(final_bytes, b"$y" | b"c")
+    }));
+    Ok(reports_synchronized_output(&response))
+}
+
+/// Asks the terminal whether it supports keyboard enhancement (the kitty keyboard protocol) by
+/// querying its current flags. A primary device attributes query is sent afterwards, and other
+/// input is appended to `input`, as with [`supports_synchronized_output`].
+pub(crate) fn supports_keyboard_enhancement(
+    device: Option<&Tty>,
+    input: &mut Vec<u8>,
+) -> io::Result<bool> {
+    let response = query(device, b"\x1b[?u\x1b[c", answered_device_attributes)?;
+    input.extend(without_sequences(&response, b"\x1b[?", |_, final_bytes| {
+        matches!(final_bytes, b"u" | b"c")
+    }));
+    Ok(reports_keyboard_flags(&response))
+}
+
+/// Asks the terminal for the position of its cursor using a device status report. Returns the
+/// zero-based column and row. Any other input read while waiting for the report is appended to
+/// `input`.
+pub(crate) fn cursor_position(device: Option<&Tty>, input: &mut Vec<u8>) -> io::Result<(u16, u16)> {
+    let response = query(device, b"\x1b[6n", |response| {
+        reported_cursor_position(response).is_some()
+    })?;
+    input.extend(without_sequences(&response, b"\x1b[", |_, final_bytes| {
+        final_bytes == b"R"
+    }));
+    let (column, row) = reported_cursor_position(&response).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Terminal did not report its cursor position",
+        )
+    })?;
+    Ok((column.saturating_sub(1), row.saturating_sub(1)))
+}
+
+/// Writes a request to the terminal, then reads its response until `is_complete` returns true for
+/// everything read so far, or [`QUERY_TIMEOUT`] passes. Returns everything read.
+///
+/// Responses arrive as input, so queries must be made in raw mode, before anything else starts
+/// reading input. The given terminal device is queried, or the controlling terminal through
+/// stdout if there is none.
+fn query(
+    device: Option<&Tty>,
+    request: &[u8],
+    is_complete: impl Fn(&[u8]) -> bool,
+) -> io::Result<Vec<u8>> {
+    let mut writer: Box<dyn Write> = match device {
+        Some(device) => Box::new(device.writer()?),
+        None => Box::new(io::stdout()),
+    };
+    writer.write_all(request)?;
+    writer.flush()?;
+
+    read_response(
+        |response, timeout| sys::read_input(response, timeout, device),
+        is_complete,
+    )
+}
+
+/// Reads input with `read` until `is_complete` returns true for everything read so far, or
+/// [`QUERY_TIMEOUT`] passes. `read` waits up to the given time for input, appends it to the
+/// buffer, and returns false if none arrived.
+fn read_response(
+    mut read: impl FnMut(&mut Vec<u8>, Duration) -> io::Result<bool>,
+    is_complete: impl Fn(&[u8]) -> bool,
+) -> io::Result<Vec<u8>> {
+    let mut response = Vec::new();
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    while !is_complete(&response) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || !read(&mut response, remaining)? {
+            break;
+        }
+    }
+    Ok(response)
+}
+
+/// Whether the given input contains a response to a primary device attributes query
+fn answered_device_attributes(input: &[u8]) -> bool {
+    private_sequences(input).any(|(_, final_bytes)| final_bytes == b"c")
+}
+
+/// Whether the given input contains a DECRQM report that synchronized output is supported
+fn reports_synchronized_output(input: &[u8]) -> bool {
+    let mode_state = private_sequences(input)
+        .filter(|(_, final_bytes)| *final_bytes == b"$y")
+        .find_map(|(parameters, _)| parameters.strip_prefix(b"2026;"));
+    // 1 and 2 mean the mode is currently set or reset, 3 and 4 that it is permanently so, and 0
+    // that it is not recognized
+    matches!(mode_state, Some(b"1" | b"2" | b"3"))
+}
+
+/// Whether the given input contains a report of the current keyboard enhancement flags
+fn reports_keyboard_flags(input: &[u8]) -> bool {
+    private_sequences(input).any(|(_, final_bytes)| final_bytes == b"u")
+}
+
+/// One-based column and row of the first cursor position report in the given input
+fn reported_cursor_position(input: &[u8]) -> Option<(u16, u16)> {
+    sequences(input, b"\x1b[")
+        .filter(|(_, final_bytes)| *final_bytes == b"R")
+        .find_map(|(parameters, _)| {
+            let parameters = std::str::from_utf8(parameters).ok()?;
+            let (row, column) = parameters.split_once(';')?;
+            Some((column.parse().ok()?, row.parse().ok()?))
+        })
+}
+
+/// Splits out the parameters and final bytes of every complete `CSI ?` sequence in the given
+/// input
+fn private_sequences(input: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    sequences(input, b"\x1b[?")
+}
+
+/// Splits out the parameters and final bytes of every complete sequence beginning with the given
+/// introducer in the given input
+fn sequences<'a>(
+    input: &'a [u8],
+    introducer: &'a [u8],
+) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+    sequence_spans(input, introducer).map(|(_, parameters, final_bytes)| (parameters, final_bytes))
+}
+
+/// Splits out sequences as [`sequences`] does, along with the range of the input each covers
+fn sequence_spans<'a>(
+    input: &'a [u8],
+    introducer: &'a [u8],
+) -> impl Iterator<Item = (Range<usize>, &'a [u8], &'a [u8])> {
+    input
+        .windows(introducer.len())
+        .enumerate()
+        .filter(move |(_, window)| *window == introducer)
+        .filter_map(move |(start, _)| {
+            let body_start = start + introducer.len();
+            let body = &input[body_start..];
+            let parameters_end = body
+                .iter()
+                .position(|byte| !(byte.is_ascii_digit() || *byte == b';'))?;
+            let final_end = body[parameters_end..]
+                .iter()
+                .position(|byte| (0x40..=0x7E).contains(byte))?;
+            let end = parameters_end + final_end + 1;
+            Some((
+                start..body_start + end,
+                &body[..parameters_end],
+                &body[parameters_end..end],
+            ))
+        })
+}
+
+/// Returns the given input without the sequences beginning with the given introducer which
+/// `is_response` accepts the parameters and final bytes of, leaving only input which was not part
+/// of a response
+fn without_sequences(
+    input: &[u8],
+    introducer: &[u8],
+    is_response: impl Fn(&[u8], &[u8]) -> bool,
+) -> Vec<u8> {
+    let mut rest = Vec::with_capacity(input.len());
+    let mut copied = 0;
+    for (range, parameters, final_bytes) in sequence_spans(input, introducer) {
+        if range.start < copied || !is_response(parameters, final_bytes) {
+            continue;
+        }
+        rest.extend_from_slice(&input[copied..range.start]);
+        copied = range.end;
+    }
+    rest.extend_from_slice(&input[copied..]);
+    rest
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        fs::File,
+        io,
+        os::fd::{AsRawFd, RawFd},
+        time::Duration,
+    };
+
+    use crate::tty::Tty;
+
+    /// Waits up to `timeout` for input from the terminal, and appends whatever is available to
+    /// `buffer`. Returns false if no input arrived in time.
+    pub(super) fn read_input(
+        buffer: &mut Vec<u8>,
+        timeout: Duration,
+        device: Option<&Tty>,
+    ) -> io::Result<bool> {
+        // Without a device, read from the same file descriptor crossterm does
+        let tty;
+        let fd: RawFd = if let Some(device) = device {
+            device.raw_fd()
+        } else if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+            libc::STDIN_FILENO
+        } else {
+            tty = File::open("/dev/tty")?;
+            tty.as_raw_fd()
+        };
+
+        let mut poll_fd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => return Ok(false),
+            _ => {}
+        }
+
+        let mut chunk = [0; 256];
+        let read = unsafe { libc::read(fd, chunk.as_mut_ptr().cast(), chunk.len()) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buffer.extend_from_slice(&chunk[..read as usize]);
+        Ok(read > 0)
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::{io, time::Duration};
+
+    use crate::tty::Tty;
+
+    /// Reading raw terminal responses is only supported on unix, so queries always time out
+    pub(super) fn read_input(
+        _buffer: &mut Vec<u8>,
+        _timeout: Duration,
+        _device: Option<&Tty>,
+    ) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
+    use super::{
+        answered_device_attributes, private_sequences, read_response, reported_cursor_position,
+        reports_keyboard_flags, reports_synchronized_output, without_sequences,
+    };
+
+    /// Reads the given chunks of input one at a time, as a terminal answering a query would
+    /// deliver them, returning the response and the chunks which were left unread
+    fn read_chunks(chunks: &[&[u8]]) -> (Vec<u8>, usize) {
+        let mut chunks = chunks.iter().collect::<VecDeque<_>>();
+        let response = read_response(
+            |buffer, _: Duration| {
+                Ok(chunks
+                    .pop_front()
+                    .map(|chunk| buffer.extend_from_slice(chunk))
+                    .is_some())
+            },
+            answered_device_attributes,
+        )
+        .expect("Failed to read response");
+        (response, chunks.len())
+    }
+
+    #[test]
+    fn private_sequences_are_split() {
+        let input = b"\x1b[?2026;2$y\x1b[?1u\x1b[?62;22c\x1b[?64";
+        let sequences = private_sequences(input).collect::<Vec<_>>();
+        assert_eq!(
+            sequences,
+            [
+                (&b"2026;2"[..], &b"$y"[..]),
+                (&b"1"[..], &b"u"[..]),
+                (&b"62;22"[..], &b"c"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn decrqm_reports_are_parsed() {
+        for (state, supported) in [(0, false), (1, true), (2, true), (3, true), (4, false)] {
+            let response = format!("\x1b[?2026;{state}$y\x1b[?62;22c");
+            assert_eq!(reports_synchronized_output(response.as_bytes()), supported);
+        }
+        // Reports of other modes, or answers to device attributes alone, do not count
+        assert!(!reports_synchronized_output(b"\x1b[?2027;1$y\x1b[?62c"));
+        assert!(!reports_synchronized_output(b"\x1b[?62c"));
+    }
+
+    #[test]
+    fn device_attributes_complete_responses() {
+        assert!(answered_device_attributes(b"\x1b[?62;22c"));
+        assert!(answered_device_attributes(b"\x1b[?1u\x1b[?c"));
+        assert!(!answered_device_attributes(b"\x1b[?62;22"));
+        assert!(!answered_device_attributes(b"\x1b[?1u"));
+    }
+
+    #[test]
+    fn split_responses_are_read_until_complete() {
+        let (response, unread) =
+            read_chunks(&[b"\x1b[?20", b"26;1$y\x1b", b"[?1u\x1b[?6", b"2c", b"after"]);
+        assert_eq!(unread, 1);
+        assert!(reports_synchronized_output(&response));
+        assert!(reports_keyboard_flags(&response));
+    }
+
+    #[test]
+    fn garbage_is_not_a_response() {
+        let (response, unread) = read_chunks(&[b"\x1b[?\xff2026;1$y", b"\x1b[?2026;x$y\x1b[?u"]);
+        assert_eq!(unread, 0);
+        assert!(!answered_device_attributes(&response));
+        assert!(!reports_synchronized_output(&response));
+        assert!(reports_keyboard_flags(&response));
+    }
+
+    #[test]
+    fn other_input_is_kept() {
+        let input = b"a\x1b[?2026;2$yb\x1b[A\x1b[?62;22cc";
+        let rest = without_sequences(input, b"\x1b[?", |_, final_bytes| {
+            matches!(final_bytes, b"$y" | b"c")
+        });
+        assert_eq!(rest, b"ab\x1b[Ac");
+
+        let input = b"\x1b[1;2R\x1b[12;40Rq";
+        assert_eq!(reported_cursor_position(input), Some((2, 1)));
+        let rest = without_sequences(input, b"\x1b[", |_, final_bytes| final_bytes == b"R");
+        assert_eq!(rest, b"q");
+    }
+}
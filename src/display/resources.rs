@@ -1,43 +1,976 @@
-use std::io::{stdout, Stdout};
+use std::{
+    io::{self, Write},
+    ops::{Add, Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
+};
 
-use bevy::prelude::*;
+use bevy::{
+    ecs::system::SystemParam,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{texture_2d, texture_storage_2d},
+            BindGroupLayout, BindGroupLayoutEntries, CachedComputePipelineId,
+            ComputePipelineDescriptor, PipelineCache, ShaderStages, StorageTextureAccess,
+            TextureFormat, TextureSampleType,
+        },
+        renderer::RenderDevice,
+    },
+};
 use crossterm::{
     event::{
-        DisableMouseCapture, EnableMouseCapture, KeyboardEnhancementFlags,
-        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+        EnableBracketedPaste, EnableFocusChange, EnableMouseCapture, Event,
+        KeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
     },
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-    ExecutableCommand,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen},
+    ExecutableCommand, QueueableCommand,
+};
+use ratatui::{
+    backend::{Backend, TestBackend},
+    buffer::Buffer,
+    layout::{Position, Rect, Size},
+    Frame, TerminalOptions, Viewport,
 };
-use ratatui::backend::CrosstermBackend;
 
-/// Ratatui terminal instance. Enters alternate screen when constructed, and exits once dropped.
-#[derive(Resource)]
-pub struct Terminal(pub ratatui::Terminal<CrosstermBackend<Stdout>>);
+use crate::{
+    input::{self, components::TerminalCursor, resources::EventQueue},
+    tty::{self, ActiveTerminal, BoxedWriter, TerminalIo, TerminalSetup, Tty},
+    KeyReleaseEmulation, KeyReleaseTimeout, SynchronizedOutput, TerminalSettings, TerminalTarget,
+};
+
+use super::{
+    backend::BoxedBackend,
+    components::OnTerminal,
+    output::{self, BackendOutput, FrameBackend, FrameOutput, OutputOptions, OutputThread},
+    query,
+};
+
+/// Ratatui terminal instance, attached to a terminal output entity. Sets up the terminal according
+/// to its [`TerminalSettings`] when constructed, and restores it once dropped. With an inline or
+/// fixed [`Viewport`], the alternate screen is not used, and the final frame stays visible in the
+/// scrollback once dropped. The plugin spawns the [`PrimaryTerminal`] from its
+/// [`TerminalTarget`], and further terminals can be spawned to draw on several terminals at once.
+/// Displays and widgets are assigned to them with
+/// [`OnTerminal`](super::components::OnTerminal). The primary terminal takes the place of the
+/// primary [`Window`], which mirrors it. Further terminals are not given windows, since windowing
+/// backends such as winit would open a real window for each, but a [`Window`] inserted on one is
+/// kept in sync in the same way.
+///
+/// Frames are written to the terminal by a background thread, so drawing never waits for output
+/// to be flushed. If the terminal cannot keep up, frames which have not been written yet are
+/// replaced by newer ones. Headless terminals and [`TerminalTarget::Backend`] instead draw frames
+/// to a ratatui backend as soon as they are drawn, so tests can inspect them with
+/// [`Terminal::last_frame`] or [`Terminal::backend`], and feed input with
+/// [`Terminal::inject_input`].
+#[derive(Component)]
+#[require(TerminalCursor)]
+pub struct Terminal {
+    terminal: ratatui::Terminal<FrameBackend>,
+    output: FrameOutput,
+    device: Option<Arc<Tty>>,
+    input: EventQueue,
+    viewport: Viewport,
+    /// Row an inline viewport is drawn from
+    inline_row: u16,
+    setup: TerminalSetup,
+    registration: u64,
+    pub(crate) reduce_colours: bool,
+    /// Whether the last frame drawn contained true colours
+    true_colour: bool,
+    /// How long keys are held for, if key releases are synthesised
+    key_release_timeout: Option<KeyReleaseTimeout>,
+}
+
+impl Terminal {
+    /// Draws a single frame, and hands it to the output thread to be written to the terminal
+    pub fn draw<F>(&mut self, render_callback: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Frame),
+    {
+        if let FrameOutput::Backend(output) = &self.output {
+            // Backends can be resized by whoever owns them, such as tests resizing a TestBackend
+            let size = output.backend.size()?;
+            if self.terminal.backend().size()? != size {
+                self.terminal.backend_mut().set_virtual_size(size);
+                self.inject_input(Event::Resize(size.width, size.height));
+            }
+        }
+        if !matches!(self.viewport, Viewport::Fullscreen) {
+            self.update_viewport()?;
+        }
+        let mut buffer = self
+            .output
+            .copy_frame(self.terminal.draw(render_callback)?.buffer);
+        self.true_colour = output::has_true_colour(&buffer);
+        if self.reduce_colours {
+            output::reduce_colours(&mut buffer);
+        }
+        let cursor = self.terminal.backend().cursor();
+        self.output.submit(buffer, cursor);
+        Ok(())
+    }
+
+    /// Current size of the terminal
+    pub fn size(&self) -> io::Result<Size> {
+        self.terminal.size()
+    }
+
+    /// Area of the terminal which is drawn to. This is the whole terminal unless an inline or
+    /// fixed [`Viewport`] is used.
+    pub fn area(&self) -> io::Result<Rect> {
+        Ok(viewport_area(&self.viewport, self.inline_row, self.size()?))
+    }
+
+    /// Moves an inline or fixed viewport to fit the current size of the terminal, since ratatui
+    /// does not resize fixed viewports itself
+    fn update_viewport(&mut self) -> io::Result<()> {
+        let area = self.area()?;
+        if self.terminal.get_frame().area() == area {
+            return Ok(());
+        }
+        self.terminal.resize(area)?;
+        self.inline_row = area.y;
+        self.setup.viewport = Some(area);
+        ActiveTerminal::update(self.registration, self.setup);
+        Ok(())
+    }
+
+    /// Whether frames are drawn to a virtual terminal or other ratatui backend rather than written
+    /// to a real terminal
+    pub fn is_headless(&self) -> bool {
+        matches!(self.output, FrameOutput::Backend(_))
+    }
+
+    /// Last frame drawn if the terminal is headless, or `None` if frames are written to a real
+    /// terminal
+    pub fn last_frame(&self) -> Option<&Buffer> {
+        match &self.output {
+            FrameOutput::Backend(output) => Some(&output.last),
+            FrameOutput::Thread(_) => None,
+        }
+    }
+
+    /// Backend frames are drawn to, if the terminal is headless and the backend is of the given
+    /// type. Headless terminals draw to a [`TestBackend`].
+    pub fn backend<B: Backend + 'static>(&self) -> Option<&B> {
+        match &self.output {
+            FrameOutput::Backend(output) => output.backend.downcast_ref(),
+            FrameOutput::Thread(_) => None,
+        }
+    }
+
+    /// Backend frames are drawn to, mutably, if the terminal is headless and the backend is of the
+    /// given type. Resizing the backend resizes the terminal when it is next drawn.
+    pub fn backend_mut<B: Backend + 'static>(&mut self) -> Option<&mut B> {
+        match &mut self.output {
+            FrameOutput::Backend(output) => output.backend.downcast_mut(),
+            FrameOutput::Thread(_) => None,
+        }
+    }
+
+    /// Blocks until every frame drawn so far has been written to the terminal
+    pub fn wait_for_output(&self) {
+        self.output.wait_idle();
+    }
+
+    /// Queues an input event as if it had been received from the terminal, such as to simulate
+    /// input in tests
+    pub fn inject_input(&self, event: Event) {
+        self.input.push(event);
+    }
+
+    /// Whether the last frame drawn to the terminal contained true colours, which
+    /// [`OutputQuality::ReducedColour`] would reduce
+    pub(crate) fn draws_true_colour(&self) -> bool {
+        self.true_colour
+    }
+
+    /// Statistics about the frames written to the terminal so far
+    pub fn stats(&self) -> OutputStats {
+        self.output.stats()
+    }
+
+    /// Queue of input events received from this terminal
+    pub(crate) fn input_queue(&self) -> &EventQueue {
+        &self.input
+    }
+
+    /// How long keys are held for, if key releases are synthesised for this terminal
+    pub(crate) fn key_release_timeout(&self) -> Option<KeyReleaseTimeout> {
+        self.key_release_timeout
+    }
+}
+
+impl Terminal {
+    /// Opens the given target, sets it up according to the given settings, and starts the output
+    /// and input threads. The alternate screen is entered if the viewport is fullscreen. Fails if
+    /// the target cannot be opened or set up, in which case any setup already done is undone.
+    pub fn new(target: &TerminalTarget, settings: &TerminalSettings) -> io::Result<Self> {
+        if let Some(size) = target.headless_size(settings.headless_fallback) {
+            if let TerminalTarget::Stdout = target {
+                warn!(
+                    "Stdout is not a terminal, rendering headless at {}x{}",
+                    size.width, size.height
+                );
+            }
+            let backend = BoxedBackend::new(TestBackend::new(size.width, size.height));
+            return Self::with_backend(backend, settings);
+        }
+        if let TerminalTarget::Backend(backend) = target {
+            let backend = backend
+                .lock()
+                .expect("Failed to get lock on terminal backend")
+                .take()
+                .ok_or_else(|| io::Error::other("Terminal backend has already been used"))?;
+            return Self::with_backend(backend, settings);
+        }
+
+        let viewport = settings.viewport.clone();
+        // Terminals of a fixed size, such as captured output, have nothing to read input from
+        let fixed_size = target.fixed_size();
+        let (writer, device) = target.open()?;
+        let io = match (&device, target) {
+            (Some(device), _) => {
+                if settings.raw_mode {
+                    device.enable_raw_mode()?;
+                }
+                TerminalIo::Device(device.clone())
+            }
+            (None, TerminalTarget::Writer(_)) => TerminalIo::Writer,
+            #[cfg(feature = "testing")]
+            (None, TerminalTarget::Capture(_)) => TerminalIo::Writer,
+            (None, _) => TerminalIo::Stdout,
+        };
+        let raw_mode = match io {
+            TerminalIo::Device(_) => settings.raw_mode,
+            TerminalIo::Headless => false,
+            // Input is still read from the controlling terminal, if there is one
+            TerminalIo::Writer => {
+                settings.raw_mode && fixed_size.is_none() && {
+                    enable_raw_mode()
+                        .inspect_err(|error| warn!("Failed to enable raw mode: {error}"))
+                        .is_ok()
+                }
+            }
+            TerminalIo::Stdout => {
+                if settings.raw_mode {
+                    enable_raw_mode()?;
+                }
+                settings.raw_mode
+            }
+        };
+        let mut pending = PendingSetup {
+            writer: Some(writer),
+            device: device.clone(),
+            setup: TerminalSetup {
+                raw_mode,
+                ..default()
+            },
+        };
+
+        let alternate_screen = matches!(viewport, Viewport::Fullscreen);
+        if alternate_screen {
+            pending.writer().execute(EnterAlternateScreen)?;
+            pending.setup.alternate_screen = true;
+        }
+        // Responses to queries would not arrive through an arbitrary writer, so writers are never
+        // queried. Without raw mode, responses would be echoed and only arrive once a line is
+        // entered, so those terminals are not queried either and are assumed to support nothing
+        let writer_only = matches!(io, TerminalIo::Writer);
+        let queryable = raw_mode && !writer_only;
+        // Input typed while the terminal is being queried is passed on to the input thread
+        let mut pending_input = Vec::new();
+        // Enhancement flags are only pushed to terminals which report support for them, or to
+        // writers, which cannot be asked
+        let keyboard_enhancement = !settings.keyboard_enhancement.is_empty()
+            && (writer_only
+                || queryable
+                    && query::supports_keyboard_enhancement(device.as_deref(), &mut pending_input)
+                        .unwrap_or_else(|error| {
+                            warn!("Failed to query keyboard enhancement support: {error}");
+                            false
+                        }));
+        info!("Keyboard enhancement enabled: {keyboard_enhancement}");
+        // Modes are recorded before they are enabled, since a failed write may have enabled them
+        // partially
+        pending.setup.mouse_capture = settings.mouse_capture;
+        pending.setup.keyboard_enhancement = keyboard_enhancement;
+        pending.setup.bracketed_paste = settings.bracketed_paste;
+        pending.setup.focus_reporting = settings.focus_reporting;
+        let writer = pending.writer();
+        if settings.mouse_capture {
+            writer.queue(EnableMouseCapture)?;
+        }
+        if keyboard_enhancement {
+            writer.queue(PushKeyboardEnhancementFlags(settings.keyboard_enhancement))?;
+        }
+        if settings.bracketed_paste {
+            writer.queue(EnableBracketedPaste)?;
+        }
+        if settings.focus_reporting {
+            writer.queue(EnableFocusChange)?;
+        }
+        writer.flush()?;
+
+        let backend = match fixed_size {
+            Some(size) => FrameBackend::headless(size),
+            None => FrameBackend::new(device.clone()),
+        };
+        let size = backend.size()?;
+        let height = size.height;
+        let inline_row = match viewport {
+            Viewport::Inline(lines) => {
+                // Terminals which cannot be queried are assumed to have the cursor at the bottom
+                let bottom = height.saturating_sub(1);
+                let row = if queryable {
+                    query::cursor_position(device.as_deref(), &mut pending_input).map_or_else(
+                        |error| {
+                            warn!("Failed to get cursor position: {error}");
+                            bottom
+                        },
+                        |(_, row)| row,
+                    )
+                } else {
+                    bottom
+                };
+                // Scroll the terminal if there are not enough lines below the cursor
+                let lines = lines.min(height);
+                let writer = pending.writer();
+                writer.write_all(&b"\n".repeat(lines.saturating_sub(1) as usize))?;
+                writer.flush()?;
+                row.min(height - lines)
+            }
+            _ => 0,
+        };
+
+        let synchronized = match settings.synchronized_output {
+            SynchronizedOutput::Auto => {
+                queryable
+                    && query::supports_synchronized_output(device.as_deref(), &mut pending_input)
+                        .unwrap_or_else(|error| {
+                            warn!("Failed to query synchronized output support: {error}");
+                            false
+                        })
+            }
+            SynchronizedOutput::Always => true,
+            SynchronizedOutput::Never => false,
+        };
+        info!("Synchronized output enabled: {synchronized}");
+
+        let emulate_key_releases = match (settings.key_release_emulation, &io) {
+            // Input is read from the controlling terminal, which enhancement was not enabled on
+            (KeyReleaseEmulation::Auto, TerminalIo::Writer) => true,
+            (KeyReleaseEmulation::Auto, _) => {
+                !(keyboard_enhancement
+                    && settings
+                        .keyboard_enhancement
+                        .contains(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))
+            }
+            (KeyReleaseEmulation::Always, _) => true,
+            (KeyReleaseEmulation::Never, _) => false,
+        };
+        info!("Key release emulation enabled: {emulate_key_releases}");
+
+        // Inline viewports are drawn as fixed viewports, which are moved when the terminal is
+        // resized
+        let area = viewport_area(&viewport, inline_row, size);
+        let options = TerminalOptions {
+            viewport: if alternate_screen {
+                Viewport::Fullscreen
+            } else {
+                Viewport::Fixed(area)
+            },
+        };
+        let setup = TerminalSetup {
+            raw_mode,
+            alternate_screen,
+            viewport: (!alternate_screen).then_some(area),
+            mouse_capture: settings.mouse_capture,
+            keyboard_enhancement,
+            bracketed_paste: settings.bracketed_paste,
+            focus_reporting: settings.focus_reporting,
+            hide_cursor: !settings.cursor_visible,
+        };
+        let terminal = ratatui::Terminal::with_options(backend, options)?;
+        // The terminal is restored when dropped from here on
+        let writer = pending
+            .writer
+            .take()
+            .expect("Terminal writer was taken during setup");
+        Ok(Self {
+            terminal,
+            output: FrameOutput::Thread(OutputThread::spawn(
+                writer,
+                OutputOptions {
+                    synchronized,
+                    clear_screen: alternate_screen,
+                    hide_cursor: setup.hide_cursor,
+                },
+            )),
+            device: device.clone(),
+            // Input can only be read once the terminal queries have been answered
+            input: match fixed_size {
+                Some(_) => EventQueue::default(),
+                None => input::systems::spawn_input_thread(
+                    device,
+                    // Input from writers is read from the controlling terminal, which the
+                    // enhancement flags were not pushed to
+                    keyboard_enhancement && !writer_only,
+                    pending_input,
+                ),
+            },
+            viewport,
+            inline_row,
+            setup,
+            registration: ActiveTerminal { io, setup }.register(),
+            reduce_colours: false,
+            true_colour: false,
+            key_release_timeout: emulate_key_releases.then_some(settings.key_release_timeout),
+        })
+    }
+
+    /// Draws frames directly to the given backend, without setting up a terminal or reading input
+    fn with_backend(backend: BoxedBackend, settings: &TerminalSettings) -> io::Result<Self> {
+        let viewport = settings.viewport.clone();
+        let size = backend.size()?;
+        // Place inline viewports at the bottom, as if the cursor was on the last line
+        let inline_row = match viewport {
+            Viewport::Inline(lines) => size.height.saturating_sub(lines),
+            _ => 0,
+        };
+        let fullscreen = matches!(viewport, Viewport::Fullscreen);
+        let area = viewport_area(&viewport, inline_row, size);
+        let options = TerminalOptions {
+            viewport: if fullscreen {
+                Viewport::Fullscreen
+            } else {
+                Viewport::Fixed(area)
+            },
+        };
+        let setup = TerminalSetup::default();
+        Ok(Self {
+            terminal: ratatui::Terminal::with_options(FrameBackend::headless(size), options)?,
+            output: FrameOutput::Backend(BackendOutput::new(
+                backend,
+                OutputOptions {
+                    synchronized: false,
+                    clear_screen: fullscreen,
+                    hide_cursor: !settings.cursor_visible,
+                },
+            )),
+            device: None,
+            input: EventQueue::default(),
+            viewport,
+            inline_row,
+            setup,
+            registration: ActiveTerminal {
+                io: TerminalIo::Headless,
+                setup,
+            }
+            .register(),
+            reduce_colours: false,
+            true_colour: false,
+            // Input is only injected, so releases are only synthesised if asked for
+            key_release_timeout: (settings.key_release_emulation == KeyReleaseEmulation::Always)
+                .then_some(settings.key_release_timeout),
+        })
+    }
+}
+
+/// Terminal which is being set up, whose setup so far is undone if it is dropped before its writer
+/// is taken, such as when a later step fails
+struct PendingSetup {
+    writer: Option<BoxedWriter>,
+    device: Option<Arc<Tty>>,
+    setup: TerminalSetup,
+}
+
+impl PendingSetup {
+    fn writer(&mut self) -> &mut BoxedWriter {
+        self.writer
+            .as_mut()
+            .expect("Terminal writer was taken during setup")
+    }
+}
+
+impl Drop for PendingSetup {
+    fn drop(&mut self) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let _ = tty::restore_output(writer, &self.setup);
+        let _ = match &self.device {
+            Some(device) if self.setup.raw_mode => device.disable_raw_mode(),
+            None if self.setup.raw_mode => disable_raw_mode(),
+            _ => Ok(()),
+        };
+    }
+}
+
+/// Area of a terminal of the given size covered by a viewport
+fn viewport_area(viewport: &Viewport, inline_row: u16, size: Size) -> Rect {
+    let screen = Rect::from((Position::ORIGIN, size));
+    match viewport {
+        Viewport::Fullscreen => screen,
+        Viewport::Inline(height) => {
+            let height = (*height).min(screen.height);
+            Rect::new(
+                0,
+                inline_row.min(screen.height - height),
+                screen.width,
+                height,
+            )
+        }
+        Viewport::Fixed(area) => area.intersection(screen),
+    }
+}
 
 impl Default for Terminal {
+    /// Sets up stdout with the default settings. Panics if this fails.
     fn default() -> Self {
-        stdout().execute(EnterAlternateScreen).unwrap();
-        stdout().execute(EnableMouseCapture).unwrap();
-        stdout()
-            .execute(PushKeyboardEnhancementFlags(
-                KeyboardEnhancementFlags::REPORT_EVENT_TYPES,
-            ))
-            .unwrap();
-        enable_raw_mode().unwrap();
-        let mut terminal = ratatui::Terminal::new(CrosstermBackend::new(stdout()))
-            .expect("Failed to create terminal");
-        terminal.clear().expect("Failed to clear terminal");
-        Self(terminal)
+        Self::new(&TerminalTarget::default(), &TerminalSettings::default())
+            .expect("Failed to set up terminal")
     }
 }
 
 impl Drop for Terminal {
     fn drop(&mut self) {
-        let mut stdout = stdout();
-        let _ = stdout.execute(PopKeyboardEnhancementFlags);
-        let _ = stdout.execute(DisableMouseCapture);
-        let _ = stdout.execute(LeaveAlternateScreen);
-        let _ = disable_raw_mode();
+        if let Some(mut writer) = self.output.shutdown() {
+            let _ = tty::restore_output(&mut writer, &self.setup);
+        }
+        let _ = match &self.device {
+            Some(device) => device.disable_raw_mode(),
+            None if self.setup.raw_mode => disable_raw_mode(),
+            None => Ok(()),
+        };
+        ActiveTerminal::unregister(self.registration);
+    }
+}
+
+/// Entity of the terminal output created by the plugin from its [`TerminalTarget`]. Displays and
+/// widgets without an [`OnTerminal`](super::components::OnTerminal) component are drawn on it.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrimaryTerminal(pub Entity);
+
+impl PrimaryTerminal {
+    /// Terminal output entity a display or widget with the given assignment is drawn on
+    pub fn resolve(&self, on_terminal: Option<&OnTerminal>) -> Entity {
+        on_terminal.map_or(self.0, |on_terminal| on_terminal.0)
+    }
+}
+
+/// System parameter for the terminal output of the [`PrimaryTerminal`], dereferencing to its
+/// [`Terminal`]. Terminals were a resource before several terminal outputs were supported, and this
+/// takes the place of `Res<Terminal>` and `ResMut<Terminal>` for code only using the primary one.
+#[derive(SystemParam)]
+pub struct PrimaryTerminalOutput<'w, 's> {
+    primary: Res<'w, PrimaryTerminal>,
+    terminals: Query<'w, 's, &'static mut Terminal>,
+}
+
+impl Deref for PrimaryTerminalOutput<'_, '_> {
+    type Target = Terminal;
+
+    fn deref(&self) -> &Terminal {
+        self.terminals
+            .get(self.primary.0)
+            .expect("Primary terminal output was despawned")
+    }
+}
+
+impl DerefMut for PrimaryTerminalOutput<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Terminal {
+        self.terminals
+            .get_mut(self.primary.0)
+            .expect("Primary terminal output was despawned")
+            .into_inner()
+    }
+}
+
+/// Cumulative statistics about the frames written to the terminal
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OutputStats {
+    /// Number of frames written to the terminal
+    pub frames_written: u64,
+    /// Number of frames which were replaced by a newer frame before they could be written
+    pub frames_dropped: u64,
+    /// Number of bytes written to the terminal
+    pub bytes_written: u64,
+    /// Total time spent writing and flushing frames
+    pub write_time: Duration,
+}
+
+impl Add for OutputStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            frames_written: self.frames_written + other.frames_written,
+            frames_dropped: self.frames_dropped + other.frames_dropped,
+            bytes_written: self.bytes_written + other.bytes_written,
+            write_time: self.write_time + other.write_time,
+        }
+    }
+}
+
+/// Quality level terminal output is currently reduced to, in order to fit within the available
+/// bandwidth. Each level includes the reductions of the levels before it. Games can read this to
+/// show a low bandwidth indicator.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OutputQuality {
+    /// No reductions
+    #[default]
+    Full,
+    /// Only every other frame is drawn
+    ReducedFrameRate,
+    /// Displays are rendered at half resolution and scaled up. Displays using
+    /// [`GpuBrailleEncoding`](super::components::GpuBrailleEncoding) keep their full resolution.
+    ReducedResolution,
+    /// True colours are replaced by the closest colours of the 256 colour palette. This level is
+    /// skipped while no terminal draws true colours, such as when only braille displays are
+    /// drawn, since it would have no effect.
+    ReducedColour,
+}
+
+impl OutputQuality {
+    /// Whether output is currently reduced in any way
+    pub fn is_reduced(&self) -> bool {
+        *self != OutputQuality::Full
+    }
+
+    /// Next lower quality level, if there is one
+    pub fn lower(&self) -> Option<Self> {
+        match self {
+            OutputQuality::Full => Some(OutputQuality::ReducedFrameRate),
+            OutputQuality::ReducedFrameRate => Some(OutputQuality::ReducedResolution),
+            OutputQuality::ReducedResolution => Some(OutputQuality::ReducedColour),
+            OutputQuality::ReducedColour => None,
+        }
+    }
+
+    /// Next higher quality level, if there is one
+    pub fn higher(&self) -> Option<Self> {
+        match self {
+            OutputQuality::Full => None,
+            OutputQuality::ReducedFrameRate => Some(OutputQuality::Full),
+            OutputQuality::ReducedResolution => Some(OutputQuality::ReducedFrameRate),
+            OutputQuality::ReducedColour => Some(OutputQuality::ReducedResolution),
+        }
+    }
+
+    /// Factor display render targets are divided by at this quality level
+    pub(crate) fn resolution_divisor(&self) -> u32 {
+        if *self >= OutputQuality::ReducedResolution {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+/// Settings for adapting [`OutputQuality`] to the bandwidth of the terminal. Quality is lowered
+/// one level at a time while the terminal cannot keep up or the byte budget is exceeded, and
+/// restored once throughput has recovered.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct AdaptiveQuality {
+    /// Whether quality is adapted at all
+    pub enabled: bool,
+    /// Maximum number of bytes per second to write to the terminal, if any. Without a budget,
+    /// quality is only lowered when frames are being dropped.
+    pub bytes_per_second: Option<u64>,
+    /// Length of the window throughput is measured over
+    pub interval: Duration,
+    /// Number of consecutive windows with spare throughput before quality is raised again
+    pub recovery_intervals: u32,
+}
+
+impl Default for AdaptiveQuality {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bytes_per_second: None,
+            interval: Duration::from_secs(1),
+            recovery_intervals: 3,
+        }
+    }
+}
+
+/// Controls when the terminal is redrawn. Sending a [`RequestRedraw`](bevy::window::RequestRedraw)
+/// event always causes a redraw on the next update, as does resizing the terminal.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RedrawMode {
+    /// Redraw every update
+    Always,
+    /// Redraw when a display's contents have changed, or a widget has been added, removed or
+    /// changed. Rendering widgets does not count as a change, but
+    /// [`TerminalWidget::update`](crate::widgets::TerminalWidget::update) does, so terminals with
+    /// widgets are redrawn every update.
+    #[default]
+    OnChange,
+    /// Only redraw when requested
+    OnRequest,
+}
+
+/// Compute pipeline which packs braille masks for displays using [`GpuBrailleEncoding`](super::components::GpuBrailleEncoding)
+#[derive(Resource)]
+pub(crate) struct BrailleMaskPipeline {
+    pub(crate) layout: BindGroupLayout,
+    pub(crate) pipeline_id: CachedComputePipelineId,
+}
+
+impl FromWorld for BrailleMaskPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(
+            "braille_mask_bind_group_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    texture_storage_2d(TextureFormat::Rgba8Uint, StorageTextureAccess::WriteOnly),
+                ),
+            ),
+        );
+
+        let shader = world
+            .resource::<AssetServer>()
+            .load::<Shader>("embedded://bevy_terminal_display/../assets/shaders/braille_mask.wgsl");
+
+        let pipeline_id = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("braille_mask_compute_pipeline".into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: vec![],
+                shader,
+                shader_defs: vec![],
+                entry_point: "pack_masks".into(),
+                zero_initialize_workgroup_memory: false,
+            });
+
+        Self {
+            layout,
+            pipeline_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use ratatui::{layout::Rect, widgets::Paragraph, Viewport};
+
+    use crate::{
+        testing::{capture::OutputCapture, snapshot::SnapshotApp},
+        TerminalDisplayPlugin, TerminalSettings, TerminalTarget,
+    };
+
+    use super::{PrimaryTerminal, PrimaryTerminalOutput, Terminal};
+
+    #[test]
+    fn inline_frame_stays_visible_after_drop() {
+        let capture = OutputCapture::new(10, 4);
+        let settings = TerminalSettings {
+            viewport: Viewport::Inline(2),
+            ..TerminalSettings::default()
+        };
+        let mut terminal =
+            Terminal::new(&capture.target(), &settings).expect("Failed to set up terminal");
+        terminal
+            .draw(|frame| frame.render_widget(Paragraph::new("first"), frame.area()))
+            .expect("Failed to draw frame");
+        terminal
+            .draw(|frame| frame.render_widget(Paragraph::new("last\nframe"), frame.area()))
+            .expect("Failed to draw frame");
+        drop(terminal);
+
+        let emulator = capture.emulate();
+        let screen = emulator.screen();
+        assert!(!screen.alternate_screen());
+        // The line after the viewport is scrolled in, so the shell prompt is printed below it
+        let rows = screen.rows(0, 10).collect::<Vec<_>>();
+        let rows = rows.iter().map(|row| row.trim_end()).collect::<Vec<_>>();
+        assert_eq!(rows, ["", "last", "frame", ""]);
+        assert_eq!(screen.cursor_position(), (3, 0));
+        assert!(!screen.hide_cursor());
+    }
+
+    /// Opens a pseudoterminal of the given size, returning its controller and the terminal
+    #[cfg(unix)]
+    fn open_pty(width: u16, height: u16) -> (std::fs::File, std::os::fd::OwnedFd) {
+        use std::{
+            ffi::CStr,
+            fs::{File, OpenOptions},
+            os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        };
+
+        let controller = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        assert!(controller >= 0, "Failed to open pseudoterminal");
+        let controller = unsafe { File::from_raw_fd(controller) };
+        let fd = controller.as_raw_fd();
+        assert_eq!(unsafe { libc::grantpt(fd) }, 0);
+        assert_eq!(unsafe { libc::unlockpt(fd) }, 0);
+        let size = libc::winsize {
+            ws_row: height,
+            ws_col: width,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        assert_eq!(unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &size) }, 0);
+        let path = unsafe { CStr::from_ptr(libc::ptsname(fd)) }
+            .to_str()
+            .expect("Pseudoterminal path is not UTF-8")
+            .to_owned();
+        let terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .expect("Failed to open pseudoterminal");
+        (controller, OwnedFd::from(terminal))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn terminals_without_raw_mode_are_not_queried() {
+        use std::{
+            io::Read,
+            os::fd::AsRawFd,
+            time::{Duration, Instant},
+        };
+
+        use crate::{SynchronizedOutput, TerminalTarget};
+
+        let (mut controller, fd) = open_pty(10, 4);
+        let settings = TerminalSettings {
+            viewport: Viewport::Inline(2),
+            synchronized_output: SynchronizedOutput::Auto,
+            raw_mode: false,
+            ..TerminalSettings::default()
+        };
+        let start = Instant::now();
+        let terminal =
+            Terminal::new(&TerminalTarget::Fd(fd), &settings).expect("Failed to set up terminal");
+        // Queries would wait for their timeout, since nothing answers them
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(
+            terminal.area().expect("Failed to get viewport area"),
+            Rect::new(0, 2, 10, 2)
+        );
+        assert!(!terminal.setup.keyboard_enhancement);
+        drop(terminal);
+
+        let flags = unsafe { libc::fcntl(controller.as_raw_fd(), libc::F_GETFL) };
+        unsafe {
+            libc::fcntl(
+                controller.as_raw_fd(),
+                libc::F_SETFL,
+                flags | libc::O_NONBLOCK,
+            )
+        };
+        let mut output = [0; 4096];
+        let read = controller.read(&mut output).unwrap_or(0);
+        let output = String::from_utf8_lossy(&output[..read]);
+        for query in ["\x1b[c", "\x1b[?u", "\x1b[6n", "\x1b[?2026$p"] {
+            assert!(!output.contains(query), "{query:?} was sent in {output:?}");
+        }
+    }
+
+    #[test]
+    fn stdout_which_is_not_a_terminal_is_headless() {
+        use std::{
+            env,
+            process::{Command, Stdio},
+        };
+
+        use ratatui::layout::Size;
+
+        // Whether stdout is a terminal is decided by how the tests are run, so the terminal is
+        // set up by this test run again in a child process, with its stdout piped
+        const CHILD: &str = "BEVY_TERMINAL_DISPLAY_HEADLESS_CHILD";
+        if env::var_os(CHILD).is_some() {
+            let mut terminal = Terminal::new(&TerminalTarget::Stdout, &TerminalSettings::default())
+                .expect("Failed to set up terminal");
+            assert!(terminal.is_headless());
+            assert_eq!(
+                terminal.size().expect("Failed to get terminal size"),
+                Size::new(80, 24)
+            );
+            terminal
+                .draw(|frame| frame.render_widget(Paragraph::new("headless"), frame.area()))
+                .expect("Failed to draw frame");
+            assert!(terminal.last_frame().is_some());
+            return;
+        }
+
+        let output = Command::new(env::current_exe().expect("Failed to get test executable"))
+            .args([
+                "--exact",
+                "display::resources::tests::stdout_which_is_not_a_terminal_is_headless",
+                "--nocapture",
+            ])
+            .env(CHILD, "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .expect("Failed to run test in child process");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output.status.success(),
+            "Child process failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(stdout.contains("1 passed"), "Test did not run: {stdout}");
+        // Nothing is written to stdout besides the test output
+        assert!(
+            !stdout.contains('\x1b'),
+            "Escape sequences were written: {stdout:?}"
+        );
+    }
+
+    #[test]
+    fn primary_terminal_output_is_the_primary_terminal() {
+        let mut app = SnapshotApp::new(12, 3);
+        app.step(1);
+        let world = app.app_mut().world_mut();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        let terminal = Terminal::new(&TerminalTarget::test(4, 2), &TerminalSettings::default())
+            .expect("Failed to set up terminal");
+        world.spawn(terminal);
+        let size = world
+            .run_system_once(|mut terminal: PrimaryTerminalOutput| {
+                terminal
+                    .draw(|frame| frame.render_widget(Paragraph::new("primary"), frame.area()))
+                    .expect("Failed to draw frame");
+                terminal.size().expect("Failed to get terminal size")
+            })
+            .expect("Failed to run system");
+        assert_eq!((size.width, size.height), (12, 3));
+
+        let terminal = world
+            .get::<Terminal>(primary)
+            .expect("Primary terminal output was despawned");
+        let frame = terminal.last_frame().expect("No frame was drawn");
+        assert_eq!(frame.cell((0, 0)).map(|cell| cell.symbol()), Some("p"));
+    }
+
+    #[test]
+    fn failed_targets_fall_back_to_headless() {
+        let target = TerminalTarget::Device("/nonexistent/pts/0".into());
+        assert!(Terminal::new(&target, &TerminalSettings::default()).is_err());
+
+        let mut app = SnapshotApp::with_plugin(TerminalDisplayPlugin {
+            target,
+            ..Default::default()
+        });
+        app.step(1);
+        let world = app.app().world();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        let terminal = world
+            .get::<Terminal>(primary)
+            .expect("Primary terminal output was not spawned");
+        assert!(terminal.is_headless());
     }
 }
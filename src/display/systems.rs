@@ -1,123 +1,478 @@
+use std::{ops::Add, time::Duration};
+
 use bevy::{
     prelude::*,
-    render::render_resource::{Extent3d, TextureFormat},
+    render::render_resource::{Extent3d, TextureUsages},
+    utils::HashMap,
+    window::RequestRedraw,
 };
-use bevy_headless_render::{components::HeadlessRenderDestination, render_assets::HeadlessRenderSource};
-use crossterm::event::Event;
-use ratatui::{
-    style::Stylize,
-    widgets::{Paragraph, Wrap},
+use bevy_dither_post_process::components::DitherPostProcessSettings;
+use bevy_headless_render::{
+    components::HeadlessRenderSource as HeadlessRenderSourceComponent,
+    render_assets::HeadlessRenderSource,
 };
+use crossterm::event::Event;
 
 use crate::{input::events::TerminalInputEvent, widgets::components::Widget};
 
-use super::resources::Terminal;
+use super::{
+    components::{OnTerminal, TerminalDisplayOutput},
+    encoding,
+    resources::{
+        AdaptiveQuality, OutputQuality, OutputStats, PrimaryTerminal, RedrawMode, Terminal,
+    },
+};
 
-const BRAILLE_CODE_MIN: u16 = 0x2800;
-const BRAILLE_CODE_MAX: u16 = 0x28FF;
+/// Takes newly read back frames out of each display's headless render destination
+pub fn receive_frames(displays: Query<&TerminalDisplayOutput>) {
+    for output in displays.iter() {
+        output.receive();
+    }
+}
 
-/// 0 3
-/// 1 4
-/// 2 5
-/// 6 7
-const BRAILLE_DOT_BIT_POSITIONS: [u8; 8] = [0, 1, 2, 6, 3, 4, 5, 7];
+/// Removes the storage usage from the threshold map of each dither post-process when it is added.
+/// The map is only ever sampled, and adapters such as software renderers do not support storage
+/// textures of its format, which would otherwise fail to be created.
+pub fn fix_dither_threshold_maps(
+    mut images: ResMut<Assets<Image>>,
+    mut asset_events: EventReader<AssetEvent<Image>>,
+    settings: Query<&DitherPostProcessSettings>,
+) {
+    let added = asset_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if added.is_empty() {
+        return;
+    }
+    for settings in settings.iter() {
+        let handle = settings.handle();
+        if !added.contains(&handle.id()) {
+            continue;
+        }
+        if let Some(image) = images.get_mut(&handle) {
+            image
+                .texture_descriptor
+                .usage
+                .remove(TextureUsages::STORAGE_BINDING);
+        }
+    }
+}
+
+/// Drawing state of a terminal output, carried between runs of [`print_to_terminal`]
+#[derive(Default)]
+pub struct DrawState {
+    /// Whether the terminal has been drawn at all
+    drawn_once: bool,
+    /// Whether a redraw was needed but skipped to reduce the frame rate
+    pending: bool,
+    /// Whether the previous redraw was skipped to reduce the frame rate
+    skipped: bool,
+}
 
-/// Prints out the contents of a render image to the terminal as braille characters
+/// Prints out the contents of each fullscreen display to the terminal it is assigned to as braille
+/// characters, and renders widgets on top of them. Depending on the [`RedrawMode`], drawing is
+/// skipped on frames where nothing has changed on a terminal. At
+/// [`OutputQuality::ReducedFrameRate`] and below, only every other redraw is performed.
+#[allow(clippy::too_many_arguments)]
 pub fn print_to_terminal(
-    mut terminal: ResMut<Terminal>,
-    image_exports: Query<&HeadlessRenderDestination>,
-    mut widgets: Query<&mut Widget>,
+    mut terminals: Query<(Entity, &mut Terminal)>,
+    primary: Res<PrimaryTerminal>,
+    redraw_mode: Res<RedrawMode>,
+    quality: Res<OutputQuality>,
+    mut redraw_requests: EventReader<RequestRedraw>,
+    mut input_events: EventReader<TerminalInputEvent>,
+    mut removed_widgets: RemovedComponents<Widget>,
+    displays: Query<(&TerminalDisplayOutput, Option<&OnTerminal>)>,
+    mut widgets: Query<(&mut Widget, Option<&OnTerminal>)>,
+    mut states: Local<HashMap<Entity, DrawState>>,
 ) {
-    for image_export in image_exports.iter() {
-        let mut image = image_export
-            .0
-            .lock()
-            .expect("Failed to get lock on output texture");
-        if image.texture_descriptor.format != TextureFormat::R8Unorm {
-            warn_once!("Extracted framebuffer texture is not R8Unorm format. Will attempt conversion, but consider changing your render texture's format.");
-            info_once!("{:?}", image);
-            match image.convert(TextureFormat::R8Unorm) {
-                Some(img) => *image = img,
-                None => error_once!(
-                    "Could not convert to R8Unorm texture format. Unexpected output may occur."
-                ),
-            };
-        }
+    let requested = redraw_requests.read().count() > 0;
+    let resized = input_events
+        .read()
+        .filter(|event| matches!(event.event, Event::Resize(..)))
+        .map(|event| event.terminal)
+        .collect::<Vec<_>>();
+    let widgets_removed = removed_widgets.read().count() > 0;
+    states.retain(|entity, _| terminals.contains(*entity));
 
-        let mut output_buffer = Vec::<char>::new();
-        let width = image.width();
-        let height = image.height();
-        let data = &image.data;
-        for character_y in (0..height).step_by(4) {
-            for character_x in (0..width).step_by(2) {
-                let mut mask: u8 = 0;
-                for offset_x in 0..2 {
-                    for offset_y in 0..4 {
-                        let x = character_x + offset_x;
-                        let y = character_y + offset_y;
-                        if x < width && y < height && data[(y * width + x) as usize] == 0xFF {
-                            mask |= 1
-                                << (BRAILLE_DOT_BIT_POSITIONS[(offset_x * 4 + offset_y) as usize]);
-                        }
-                    }
+    let mut drawn = false;
+    for (entity, mut terminal) in terminals.iter_mut() {
+        let state = states.entry(entity).or_default();
+        let redraw = !state.drawn_once
+            || requested
+            || resized.contains(&entity)
+            || match *redraw_mode {
+                RedrawMode::Always => true,
+                RedrawMode::OnChange => {
+                    // Embedded displays may be drawn by widgets on any terminal
+                    widgets_removed
+                        || displays.iter().any(|(output, on_terminal)| {
+                            (output.is_embedded() || primary.resolve(on_terminal) == entity)
+                                && output.has_new_frame()
+                        })
+                        || widgets.iter_mut().any(|(widget, on_terminal)| {
+                            primary.resolve(on_terminal) == entity && widget.is_changed()
+                        })
                 }
-                output_buffer.push(braille_char(mask));
-            }
+                RedrawMode::OnRequest => false,
+            };
+        state.pending |= redraw;
+        if !state.pending {
+            continue;
+        }
+        let skip = *quality >= OutputQuality::ReducedFrameRate && !state.skipped;
+        state.skipped = skip;
+        if skip {
+            continue;
         }
+        state.drawn_once = true;
+        state.pending = false;
+        drawn = true;
 
-        let string = output_buffer.into_iter().collect::<String>();
+        terminal.reduce_colours = *quality >= OutputQuality::ReducedColour;
         terminal
-            .0
             .draw(|frame| {
-                frame.render_widget(
-                    Paragraph::new(string)
-                        .white()
-                        .wrap(Wrap { trim: true }),
-                    frame.area(),
-                );
+                for (output, _) in displays.iter().filter(|(output, on_terminal)| {
+                    !output.is_embedded() && primary.resolve(*on_terminal) == entity
+                }) {
+                    let area = frame.area();
+                    output.draw(area, frame.buffer_mut());
+                }
 
                 let mut active_widgets = widgets
                     .iter_mut()
-                    .filter(|widget| widget.enabled)
+                    .filter(|(widget, on_terminal)| {
+                        widget.enabled && primary.resolve(*on_terminal) == entity
+                    })
+                    .map(|(widget, _)| widget)
                     .collect::<Vec<_>>();
-                active_widgets.sort_by(|a, b| a.depth.cmp(&b.depth));
+                active_widgets.sort_by_key(|widget| widget.depth);
                 for mut widget in active_widgets {
-                    widget.widget.render(frame, frame.area());
+                    // Rendering should not count as a change to the widget
+                    widget
+                        .bypass_change_detection()
+                        .widget
+                        .render(frame, frame.area());
                 }
             })
-            .expect("Failed to draw terminal frame");
+            .unwrap_or_else(|error| error!("Failed to draw terminal frame: {error}"));
+    }
+
+    if drawn {
+        for (output, _) in displays.iter() {
+            output.mark_drawn();
+        }
+    }
+}
+
+/// Throughput measured over the current window of [`adapt_output_quality`]
+#[derive(Default)]
+pub struct QualityWindow {
+    /// Time at which the current window started
+    start: Option<Duration>,
+    /// Output statistics at the start of the current window
+    stats: OutputStats,
+    /// Number of consecutive windows in which throughput had room to spare
+    calm_windows: u32,
+}
+
+/// Measures how quickly frames are written to the terminal, and lowers the [`OutputQuality`] one
+/// level at a time while the terminal cannot keep up or the byte budget of [`AdaptiveQuality`] is
+/// exceeded. Quality is raised again once throughput has recovered. Colours are only reduced if a
+/// terminal draws true colours.
+pub fn adapt_output_quality(
+    terminals: Query<&Terminal>,
+    settings: Res<AdaptiveQuality>,
+    mut quality: ResMut<OutputQuality>,
+    time: Res<Time<Real>>,
+    mut window: Local<QualityWindow>,
+) {
+    if !settings.enabled {
+        if quality.is_reduced() {
+            *quality = OutputQuality::Full;
+        }
+        return;
+    }
+
+    let stats = terminals
+        .iter()
+        .map(Terminal::stats)
+        .fold(OutputStats::default(), Add::add);
+    let true_colour = terminals.iter().any(Terminal::draws_true_colour);
+    if let Some(next) = window.next_quality(time.elapsed(), stats, &settings, *quality, true_colour)
+    {
+        *quality = next;
     }
 }
 
-/// Utility function to convert a u8 into the corresponding braille character
-fn braille_char(mask: u8) -> char {
-    match char::from_u32((BRAILLE_CODE_MIN + mask as u16) as u32) {
-        Some(character) => {
-            if character as u16 > BRAILLE_CODE_MAX {
-                panic!("Number too big!")
+impl QualityWindow {
+    /// Measures throughput up to the given time from the total output statistics, returning the
+    /// quality level to change to if the window has ended and calls for a change
+    fn next_quality(
+        &mut self,
+        now: Duration,
+        stats: OutputStats,
+        settings: &AdaptiveQuality,
+        quality: OutputQuality,
+        true_colour: bool,
+    ) -> Option<OutputQuality> {
+        let Some(start) = self.start else {
+            self.start = Some(now);
+            self.stats = stats;
+            return None;
+        };
+        let elapsed = now.saturating_sub(start);
+        if elapsed < settings.interval || elapsed.is_zero() {
+            return None;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        // Totals can decrease when terminal outputs are despawned
+        let byte_rate =
+            stats.bytes_written.saturating_sub(self.stats.bytes_written) as f64 / seconds;
+        let busy = stats
+            .write_time
+            .saturating_sub(self.stats.write_time)
+            .as_secs_f64()
+            / seconds;
+        let dropped = stats
+            .frames_dropped
+            .saturating_sub(self.stats.frames_dropped);
+        let budget = settings.bytes_per_second.map(|budget| budget as f64);
+        self.start = Some(now);
+        self.stats = stats;
+
+        let congested =
+            dropped > 0 || busy > 0.9 || budget.is_some_and(|budget| byte_rate > budget);
+        let calm =
+            dropped == 0 && busy < 0.5 && budget.is_none_or(|budget| byte_rate < budget / 2.0);
+        let next = if congested {
+            self.calm_windows = 0;
+            // Reducing colours has no effect when there are no true colours to reduce
+            quality
+                .lower()
+                .filter(|next| *next != OutputQuality::ReducedColour || true_colour)
+        } else if calm {
+            self.calm_windows += 1;
+            if self.calm_windows < settings.recovery_intervals {
+                return None;
             }
-            character
+            self.calm_windows = 0;
+            quality.higher()
+        } else {
+            self.calm_windows = 0;
+            None
+        };
+
+        if let Some(next) = next {
+            info!(
+                "Terminal output quality changed from {:?} to {:?} ({:.0} bytes/s, {:.0}% busy, {} frames dropped)",
+                quality,
+                next,
+                byte_rate,
+                busy * 100.0,
+                dropped
+            );
         }
-        None => panic!("Error converting character!"),
+        next
     }
 }
 
-/// Watches for terminal resize events and resizes the render image accordingly
+/// Watches for terminal resize events and resizes the render image of each fullscreen display on
+/// the resized terminal accordingly. Render images are also resized when a display is created or
+/// assigned to another terminal, and when the [`OutputQuality`] changes resolution.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub fn resize_handling(
     mut images: ResMut<Assets<Image>>,
     mut sources: ResMut<Assets<HeadlessRenderSource>>,
+    displays: Query<(
+        Ref<TerminalDisplayOutput>,
+        &HeadlessRenderSourceComponent,
+        Option<Ref<OnTerminal>>,
+    )>,
+    terminals: Query<&Terminal>,
+    primary: Res<PrimaryTerminal>,
+    quality: Res<OutputQuality>,
+    mut removed_assignments: RemovedComponents<OnTerminal>,
     mut event_reader: EventReader<TerminalInputEvent>,
 ) {
-    for event in event_reader.read() {
-        if let Event::Resize(w, h) = event.0 {
-            for source in sources.iter_mut() {
-                let image = images.get_mut(&source.1 .0).unwrap();
-                image.resize(Extent3d {
-                    width: w as u32 * 2,
-                    height: h as u32 * 4,
-                    depth_or_array_layers: 1,
-                });
-            }
+    let resized = event_reader
+        .read()
+        .filter(|event| matches!(event.event, Event::Resize(..)))
+        .map(|event| event.terminal)
+        .collect::<Vec<_>>();
+    let unassigned = removed_assignments.read().collect::<Vec<_>>();
+
+    for (output, source, on_terminal) in
+        displays.iter().filter(|(output, ..)| !output.is_embedded())
+    {
+        let terminal = primary.resolve(on_terminal.as_deref());
+        let reassigned = output.is_changed()
+            || on_terminal.is_some_and(|on_terminal| on_terminal.is_changed())
+            || unassigned.contains(&output.entity);
+        if !(resized.contains(&terminal) || reassigned || quality.is_changed()) {
+            continue;
         }
+        // Displays fill the terminal's viewport, which may be smaller than the terminal
+        let Some(area) = terminals
+            .get(terminal)
+            .ok()
+            .and_then(|terminal| terminal.area().ok())
+        else {
+            continue;
+        };
+        let size = UVec2::new(area.width as u32, area.height as u32);
+        resize_display(
+            &mut images,
+            &mut sources,
+            &output,
+            source,
+            display_size(&output, size * UVec2::new(2, 4), *quality),
+        );
+    }
+}
+
+/// Resizes the render image of each display embedded in a camera view to fit the area it was last
+/// drawn in
+pub fn camera_view_resize_handling(
+    mut images: ResMut<Assets<Image>>,
+    mut sources: ResMut<Assets<HeadlessRenderSource>>,
+    displays: Query<(&TerminalDisplayOutput, &HeadlessRenderSourceComponent)>,
+    quality: Res<OutputQuality>,
+) {
+    for (output, source) in displays.iter() {
+        let Some(size) = *output
+            .viewport
+            .lock()
+            .expect("Failed to get lock on display viewport")
+        else {
+            continue;
+        };
+        let size = display_size(output, size, *quality);
+        resize_display(&mut images, &mut sources, output, source, size);
+    }
+}
+
+/// Size to render a display at for the given full resolution size and output quality. Displays
+/// using GPU braille encoding are always rendered at full resolution, since their masks cannot be
+/// scaled up.
+fn display_size(output: &TerminalDisplayOutput, size: UVec2, quality: OutputQuality) -> UVec2 {
+    if output.mask_target.is_some() {
+        size
+    } else {
+        let divisor = quality.resolution_divisor();
+        UVec2::new(size.x.div_ceil(divisor), size.y.div_ceil(divisor))
+    }
+}
+
+/// Resizes the render target of a display, along with its braille mask target if it has one, if
+/// its size differs from the given one
+fn resize_display(
+    images: &mut Assets<Image>,
+    sources: &mut Assets<HeadlessRenderSource>,
+    output: &TerminalDisplayOutput,
+    source: &HeadlessRenderSourceComponent,
+    size: UVec2,
+) {
+    let size = size.max(UVec2::ONE);
+    if images
+        .get(&output.render_target)
+        .is_none_or(|image| image.size() == size)
+    {
+        return;
+    }
+
+    resize_image(images, &output.render_target, size);
+    if let Some(mask_target) = &output.mask_target {
+        resize_image(images, mask_target, encoding::braille_mask_size(size));
+    }
+    // Mark source as modified so that its readback buffer is recreated at the new size
+    sources.get_mut(&source.0);
+}
+
+fn resize_image(images: &mut Assets<Image>, handle: &Handle<Image>, size: UVec2) {
+    if let Some(image) = images.get_mut(handle) {
+        image.resize(Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::display::resources::{AdaptiveQuality, OutputQuality, OutputStats};
+
+    use super::QualityWindow;
+
+    /// Qualities chosen at the end of each one second window, given the bytes written in each
+    fn adapt(budget: u64, true_colour: bool, windows: &[u64]) -> Vec<OutputQuality> {
+        let settings = AdaptiveQuality {
+            bytes_per_second: Some(budget),
+            ..Default::default()
+        };
+        let mut window = QualityWindow::default();
+        let mut quality = OutputQuality::Full;
+        let mut stats = OutputStats::default();
+        let mut now = Duration::ZERO;
+        window.next_quality(now, stats, &settings, quality, true_colour);
+        windows
+            .iter()
+            .map(|bytes| {
+                now += settings.interval;
+                stats.bytes_written += bytes;
+                if let Some(next) = window.next_quality(now, stats, &settings, quality, true_colour)
+                {
+                    quality = next;
+                }
+                quality
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quality_is_lowered_over_budget_and_recovers() {
+        use OutputQuality::*;
+
+        let qualities = adapt(
+            1000,
+            true,
+            &[2000, 2000, 2000, 2000, 100, 100, 100, 700, 100],
+        );
+        assert_eq!(
+            qualities,
+            [
+                ReducedFrameRate,
+                ReducedResolution,
+                ReducedColour,
+                // There is nothing lower
+                ReducedColour,
+                ReducedColour,
+                ReducedColour,
+                // Quality is raised after the recovery intervals
+                ReducedResolution,
+                // Throughput between half and all of the budget keeps the current quality
+                ReducedResolution,
+                ReducedResolution,
+            ]
+        );
+    }
+
+    #[test]
+    fn colours_are_only_reduced_with_true_colour() {
+        use OutputQuality::*;
+
+        let qualities = adapt(1000, false, &[2000, 2000, 2000]);
+        assert_eq!(
+            qualities,
+            [ReducedFrameRate, ReducedResolution, ReducedResolution]
+        );
     }
 }
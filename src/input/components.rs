@@ -1,4 +1,27 @@
 use bevy::prelude::*;
 
-#[derive(Debug, Component)]
-pub struct DummyWindow;
+/// Position of the mouse cursor over a terminal output, updated from the mouse events it receives.
+/// Added to every terminal output entity.
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq)]
+pub struct TerminalCursor {
+    /// Cell the cursor is over, or `None` if no mouse event has been received yet
+    pub cell: Option<UVec2>,
+    /// Position of the cursor in display pixels, at the centre of its cell. Each cell covers
+    /// [`PIXELS_PER_CELL`] pixels, as with braille displays.
+    pub position: Option<Vec2>,
+}
+
+/// Size of a terminal cell in the pixels of a braille display, which cursor positions are
+/// reported in
+pub const PIXELS_PER_CELL: Vec2 = Vec2::new(2.0, 4.0);
+
+impl TerminalCursor {
+    /// Moves the cursor to the given cell, returning whether its position changed
+    pub(crate) fn move_to(&mut self, cell: UVec2) -> bool {
+        let position = (cell.as_vec2() + 0.5) * PIXELS_PER_CELL;
+        let moved = self.position != Some(position);
+        self.cell = Some(cell);
+        self.position = Some(position);
+        moved
+    }
+}
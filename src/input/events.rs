@@ -1,6 +1,43 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
 use bevy::prelude::*;
 use crossterm::event::Event;
 
-/// An event triggered when a crossterm input event is received
+/// Sequence number of the next input event to arrive
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// An event triggered when a crossterm input event is received. Events from each terminal are sent
+/// in the order they arrived, and events from different terminals can be ordered by their
+/// [`arrival`](Self::arrival).
 #[derive(Event)]
-pub struct TerminalInputEvent(pub Event);
+pub struct TerminalInputEvent {
+    /// Input event received
+    pub event: Event,
+    /// Terminal output entity the event was received from
+    pub terminal: Entity,
+    /// When the event arrived
+    pub arrival: InputArrival,
+}
+
+/// When an input event arrived from a terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InputArrival {
+    /// Time at which the event was read from the terminal, or injected
+    pub time: Instant,
+    /// Position of the event among all input events received from every terminal output, which
+    /// increases with each event
+    pub sequence: u64,
+}
+
+impl InputArrival {
+    /// Arrival of an event which has just been received
+    pub(crate) fn now() -> Self {
+        Self {
+            time: Instant::now(),
+            sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
@@ -1,3 +1,6 @@
+/// Components for this module
+pub mod components;
+
 /// Events for this module
 pub mod events;
 
@@ -7,5 +10,5 @@ pub mod resources;
 /// Systems for this module
 pub(crate) mod systems;
 
-/// Components for this module
-pub(crate) mod components;
+/// Parser for input read from terminal devices
+pub(crate) mod parser;
@@ -0,0 +1,805 @@
+use crossterm::event::{
+    Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers, MediaKeyCode,
+    ModifierKeyCode, MouseButton, MouseEvent, MouseEventKind,
+};
+
+use super::resources::InputEvent;
+
+/// Longest escape sequence accepted before the input is assumed to be garbage
+const MAX_SEQUENCE_LENGTH: usize = 64;
+
+/// Sequence terminating bracketed paste
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Result of trying to parse a single event from the start of the input
+enum Parsed {
+    /// More input is needed to complete the event
+    Incomplete,
+    /// The given number of bytes were consumed, producing an event if they were recognized
+    Consumed(usize, Option<InputEvent>),
+}
+
+/// Incremental parser turning raw input from a terminal device into crossterm events. Used instead
+/// of crossterm's own parser for terminals other than the controlling terminal, which crossterm
+/// cannot read from, and to keep the base layout keys of the kitty keyboard protocol.
+#[derive(Default)]
+pub(crate) struct InputParser {
+    buffer: Vec<u8>,
+    paste: Option<Vec<u8>>,
+}
+
+impl InputParser {
+    /// Parses the given input, appending complete events to `events`. Incomplete sequences are
+    /// kept until more input arrives, unless `more` is false, in which case a lone escape byte
+    /// is treated as the escape key.
+    pub(crate) fn parse(&mut self, input: &[u8], more: bool, events: &mut Vec<InputEvent>) {
+        self.buffer.extend_from_slice(input);
+        let mut start = 0;
+        while start < self.buffer.len() {
+            let remaining = &self.buffer[start..];
+            if let Some(paste) = &mut self.paste {
+                match find(remaining, PASTE_END) {
+                    Some(end) => {
+                        paste.extend_from_slice(&remaining[..end]);
+                        let paste = self.paste.take().unwrap_or_default();
+                        let paste = String::from_utf8_lossy(&paste).into_owned();
+                        events.push(Event::Paste(paste).into());
+                        start += end + PASTE_END.len();
+                    }
+                    None => {
+                        // Keep anything which could be the start of the terminating sequence
+                        let keep = (1..PASTE_END.len())
+                            .rev()
+                            .find(|length| remaining.ends_with(&PASTE_END[..*length]))
+                            .unwrap_or(0);
+                        paste.extend_from_slice(&remaining[..remaining.len() - keep]);
+                        start = self.buffer.len() - keep;
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            match parse_event(remaining, more) {
+                Parsed::Incomplete => break,
+                Parsed::Consumed(length, event) => {
+                    start += length;
+                    if remaining.starts_with(b"\x1b[200~") {
+                        self.paste = Some(Vec::new());
+                    }
+                    events.extend(event);
+                }
+            }
+        }
+        self.buffer.drain(..start);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_event(input: &[u8], more: bool) -> Parsed {
+    match input {
+        [] => Parsed::Incomplete,
+        [0x1B] if more => Parsed::Incomplete,
+        [0x1B] | [0x1B, 0x1B, ..] => Parsed::Consumed(1, Some(key(KeyCode::Esc).into())),
+        [0x1B, b'[', ..] => parse_csi(input),
+        [0x1B, b'O', ..] => parse_ss3(input),
+        [0x1B, rest @ ..] => match parse_plain(rest) {
+            Parsed::Consumed(
+                length,
+                Some(InputEvent {
+                    event: Event::Key(mut event),
+                    ..
+                }),
+            ) => {
+                event.modifiers |= KeyModifiers::ALT;
+                Parsed::Consumed(length + 1, Some(Event::Key(event).into()))
+            }
+            Parsed::Consumed(length, event) => Parsed::Consumed(length + 1, event),
+            Parsed::Incomplete => Parsed::Incomplete,
+        },
+        _ => parse_plain(input),
+    }
+}
+
+/// Parses a single byte or UTF-8 character which is not part of an escape sequence
+fn parse_plain(input: &[u8]) -> Parsed {
+    let event = match input[0] {
+        b'\r' | b'\n' => key(KeyCode::Enter),
+        b'\t' => key(KeyCode::Tab),
+        0x7F | 0x08 => key(KeyCode::Backspace),
+        0x00 => key_with(KeyCode::Char(' '), KeyModifiers::CONTROL),
+        byte @ 0x01..=0x1A => key_with(
+            KeyCode::Char((b'a' + byte - 1) as char),
+            KeyModifiers::CONTROL,
+        ),
+        byte @ 0x1C..=0x1F => key_with(
+            KeyCode::Char((b'4' + byte - 0x1C) as char),
+            KeyModifiers::CONTROL,
+        ),
+        byte => {
+            let length = match byte {
+                0x00..=0x7F => 1,
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => return Parsed::Consumed(1, None),
+            };
+            if input.len() < length {
+                return Parsed::Incomplete;
+            }
+            let Some(character) = std::str::from_utf8(&input[..length])
+                .ok()
+                .and_then(|text| text.chars().next())
+            else {
+                return Parsed::Consumed(1, None);
+            };
+            let modifiers = if character.is_uppercase() {
+                KeyModifiers::SHIFT
+            } else {
+                KeyModifiers::NONE
+            };
+            let event = key_with(KeyCode::Char(character), modifiers);
+            return Parsed::Consumed(length, Some(event.into()));
+        }
+    };
+    Parsed::Consumed(1, Some(event.into()))
+}
+
+/// Parses an `ESC O` sequence, sent for function and cursor keys in application mode
+fn parse_ss3(input: &[u8]) -> Parsed {
+    let Some(&final_byte) = input.get(2) else {
+        return Parsed::Incomplete;
+    };
+    let code = match final_byte {
+        b'A' => KeyCode::Up,
+        b'B' => KeyCode::Down,
+        b'C' => KeyCode::Right,
+        b'D' => KeyCode::Left,
+        b'H' => KeyCode::Home,
+        b'F' => KeyCode::End,
+        b'P' => KeyCode::F(1),
+        b'Q' => KeyCode::F(2),
+        b'R' => KeyCode::F(3),
+        b'S' => KeyCode::F(4),
+        _ => return Parsed::Consumed(3, None),
+    };
+    Parsed::Consumed(3, Some(key(code).into()))
+}
+
+/// Parses an `ESC [` sequence
+fn parse_csi(input: &[u8]) -> Parsed {
+    // Legacy X10 mouse reports carry three raw bytes after the final byte
+    if input.get(2) == Some(&b'M') {
+        return match input.get(3..6) {
+            Some(&[button, column, row]) => Parsed::Consumed(
+                6,
+                parse_mouse(
+                    button.saturating_sub(32) as u16,
+                    column.saturating_sub(33) as u16,
+                    row.saturating_sub(33) as u16,
+                    false,
+                )
+                .map(Into::into),
+            ),
+            _ => Parsed::Incomplete,
+        };
+    }
+
+    let Some(final_index) = input[2..]
+        .iter()
+        .position(|byte| (0x40..=0x7E).contains(byte))
+        .map(|index| index + 2)
+    else {
+        return if input.len() > MAX_SEQUENCE_LENGTH {
+            Parsed::Consumed(input.len(), None)
+        } else {
+            Parsed::Incomplete
+        };
+    };
+    let length = final_index + 1;
+    let Ok(parameters) = std::str::from_utf8(&input[2..final_index]) else {
+        return Parsed::Consumed(length, None);
+    };
+    let final_byte = input[final_index];
+
+    // Responses to queries, which are not input
+    if parameters.starts_with('?') {
+        return Parsed::Consumed(length, None);
+    }
+    if let Some(parameters) = parameters.strip_prefix('<') {
+        let event = parse_sgr_mouse(parameters, final_byte);
+        return Parsed::Consumed(length, event.map(Into::into));
+    }
+
+    let event = match final_byte {
+        b'I' if parameters.is_empty() => Some(Event::FocusGained),
+        b'O' if parameters.is_empty() => Some(Event::FocusLost),
+        b'Z' => Some(key_with(KeyCode::BackTab, KeyModifiers::SHIFT)),
+        b'A' | b'B' | b'C' | b'D' | b'H' | b'F' | b'P' | b'Q' | b'R' | b'S' => {
+            let code = match final_byte {
+                b'A' => KeyCode::Up,
+                b'B' => KeyCode::Down,
+                b'C' => KeyCode::Right,
+                b'D' => KeyCode::Left,
+                b'H' => KeyCode::Home,
+                b'F' => KeyCode::End,
+                b'P' => KeyCode::F(1),
+                b'Q' => KeyCode::F(2),
+                b'R' => KeyCode::F(3),
+                _ => KeyCode::F(4),
+            };
+            Some(modified_key(code, parameters.split(';').nth(1)))
+        }
+        b'~' => parse_tilde(parameters),
+        b'u' => return Parsed::Consumed(length, parse_kitty_key(parameters)),
+        _ => None,
+    };
+    Parsed::Consumed(length, event.map(Into::into))
+}
+
+/// Parses a `CSI number ; modifiers ~` key sequence
+fn parse_tilde(parameters: &str) -> Option<Event> {
+    let mut fields = parameters.split(';');
+    let number = fields.next()?.split(':').next()?.parse::<u16>().ok()?;
+    let code = match number {
+        1 | 7 => KeyCode::Home,
+        2 => KeyCode::Insert,
+        3 => KeyCode::Delete,
+        4 | 8 => KeyCode::End,
+        5 => KeyCode::PageUp,
+        6 => KeyCode::PageDown,
+        11..=15 => KeyCode::F((number - 10) as u8),
+        17..=21 => KeyCode::F((number - 11) as u8),
+        23..=26 => KeyCode::F((number - 12) as u8),
+        28 | 29 => KeyCode::F((number - 13) as u8),
+        31..=34 => KeyCode::F((number - 14) as u8),
+        // Start of bracketed paste, handled by the caller
+        _ => return None,
+    };
+    Some(modified_key(code, fields.next()))
+}
+
+/// Parses a `CSI code:shifted:base ; modifiers u` key sequence of the kitty keyboard protocol.
+/// The shifted and base layout keys are only sent if alternate keys are reported.
+fn parse_kitty_key(parameters: &str) -> Option<InputEvent> {
+    let mut fields = parameters.split(';');
+    let mut codes = fields.next()?.split(':');
+    let code = codes.next()?.parse::<u32>().ok()?;
+    let shifted = codes
+        .next()
+        .and_then(|code| code.parse::<u32>().ok())
+        .and_then(char::from_u32);
+    let base_key = codes
+        .next()
+        .and_then(|code| code.parse::<u32>().ok())
+        .and_then(char::from_u32)
+        .map(KeyCode::Char);
+    let Event::Key(mut event) = modified_key(kitty_key_code(code)?, fields.next()) else {
+        return None;
+    };
+
+    match kitty_keypad_code(code) {
+        Some(code) => {
+            event.code = code;
+            event.state |= KeyEventState::KEYPAD;
+        }
+        None => {
+            if let (KeyCode::Char(_), Some(shifted), true) = (
+                event.code,
+                shifted,
+                event.modifiers.contains(KeyModifiers::SHIFT),
+            ) {
+                event.code = KeyCode::Char(shifted);
+            }
+        }
+    }
+    if let KeyCode::Modifier(modifier) = event.code {
+        // Modifier keys report their own modifier bit as set while pressed
+        event.modifiers |= match modifier {
+            ModifierKeyCode::LeftShift | ModifierKeyCode::RightShift => KeyModifiers::SHIFT,
+            ModifierKeyCode::LeftControl | ModifierKeyCode::RightControl => KeyModifiers::CONTROL,
+            ModifierKeyCode::LeftAlt | ModifierKeyCode::RightAlt => KeyModifiers::ALT,
+            ModifierKeyCode::LeftSuper | ModifierKeyCode::RightSuper => KeyModifiers::SUPER,
+            ModifierKeyCode::LeftHyper | ModifierKeyCode::RightHyper => KeyModifiers::HYPER,
+            ModifierKeyCode::LeftMeta | ModifierKeyCode::RightMeta => KeyModifiers::META,
+            _ => KeyModifiers::NONE,
+        };
+    }
+    Some(InputEvent {
+        event: Event::Key(event),
+        base_key,
+    })
+}
+
+/// Maps a kitty keyboard protocol key code to a crossterm key code
+fn kitty_key_code(code: u32) -> Option<KeyCode> {
+    Some(match code {
+        9 => KeyCode::Tab,
+        13 => KeyCode::Enter,
+        27 => KeyCode::Esc,
+        127 | 8 => KeyCode::Backspace,
+        57358 => KeyCode::CapsLock,
+        57359 => KeyCode::ScrollLock,
+        57360 => KeyCode::NumLock,
+        57361 => KeyCode::PrintScreen,
+        57362 => KeyCode::Pause,
+        57363 => KeyCode::Menu,
+        57376..=57398 => KeyCode::F((code - 57376 + 13) as u8),
+        57399..=57427 => KeyCode::Char('\0'),
+        57428 => KeyCode::Media(MediaKeyCode::Play),
+        57429 => KeyCode::Media(MediaKeyCode::Pause),
+        57430 => KeyCode::Media(MediaKeyCode::PlayPause),
+        57431 => KeyCode::Media(MediaKeyCode::Reverse),
+        57432 => KeyCode::Media(MediaKeyCode::Stop),
+        57433 => KeyCode::Media(MediaKeyCode::FastForward),
+        57434 => KeyCode::Media(MediaKeyCode::Rewind),
+        57435 => KeyCode::Media(MediaKeyCode::TrackNext),
+        57436 => KeyCode::Media(MediaKeyCode::TrackPrevious),
+        57437 => KeyCode::Media(MediaKeyCode::Record),
+        57438 => KeyCode::Media(MediaKeyCode::LowerVolume),
+        57439 => KeyCode::Media(MediaKeyCode::RaiseVolume),
+        57440 => KeyCode::Media(MediaKeyCode::MuteVolume),
+        57441 => KeyCode::Modifier(ModifierKeyCode::LeftShift),
+        57442 => KeyCode::Modifier(ModifierKeyCode::LeftControl),
+        57443 => KeyCode::Modifier(ModifierKeyCode::LeftAlt),
+        57444 => KeyCode::Modifier(ModifierKeyCode::LeftSuper),
+        57445 => KeyCode::Modifier(ModifierKeyCode::LeftHyper),
+        57446 => KeyCode::Modifier(ModifierKeyCode::LeftMeta),
+        57447 => KeyCode::Modifier(ModifierKeyCode::RightShift),
+        57448 => KeyCode::Modifier(ModifierKeyCode::RightControl),
+        57449 => KeyCode::Modifier(ModifierKeyCode::RightAlt),
+        57450 => KeyCode::Modifier(ModifierKeyCode::RightSuper),
+        57451 => KeyCode::Modifier(ModifierKeyCode::RightHyper),
+        57452 => KeyCode::Modifier(ModifierKeyCode::RightMeta),
+        57453 => KeyCode::Modifier(ModifierKeyCode::IsoLevel3Shift),
+        57454 => KeyCode::Modifier(ModifierKeyCode::IsoLevel5Shift),
+        _ => KeyCode::Char(char::from_u32(code)?),
+    })
+}
+
+/// Maps a kitty keyboard protocol keypad key code to the crossterm key code it produces
+fn kitty_keypad_code(code: u32) -> Option<KeyCode> {
+    Some(match code {
+        57399..=57408 => KeyCode::Char(char::from_digit(code - 57399, 10)?),
+        57409 => KeyCode::Char('.'),
+        57410 => KeyCode::Char('/'),
+        57411 => KeyCode::Char('*'),
+        57412 => KeyCode::Char('-'),
+        57413 => KeyCode::Char('+'),
+        57414 => KeyCode::Enter,
+        57415 => KeyCode::Char('='),
+        57416 => KeyCode::Char(','),
+        57417 => KeyCode::Left,
+        57418 => KeyCode::Right,
+        57419 => KeyCode::Up,
+        57420 => KeyCode::Down,
+        57421 => KeyCode::PageUp,
+        57422 => KeyCode::PageDown,
+        57423 => KeyCode::Home,
+        57424 => KeyCode::End,
+        57425 => KeyCode::Insert,
+        57426 => KeyCode::Delete,
+        57427 => KeyCode::KeypadBegin,
+        _ => return None,
+    })
+}
+
+/// Builds a key event from a modifier field of the form `modifiers[:event type]`
+fn modified_key(code: KeyCode, field: Option<&str>) -> Event {
+    let mut parts = field.unwrap_or_default().split(':');
+    let mask = parts
+        .next()
+        .and_then(|mask| mask.parse::<u8>().ok())
+        .unwrap_or(1)
+        .saturating_sub(1);
+    let kind = match parts.next() {
+        Some("2") => KeyEventKind::Repeat,
+        Some("3") => KeyEventKind::Release,
+        _ => KeyEventKind::Press,
+    };
+
+    let mut modifiers = KeyModifiers::NONE;
+    let mut state = KeyEventState::NONE;
+    for (bit, modifier) in [
+        KeyModifiers::SHIFT,
+        KeyModifiers::ALT,
+        KeyModifiers::CONTROL,
+        KeyModifiers::SUPER,
+        KeyModifiers::HYPER,
+        KeyModifiers::META,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        if mask & (1 << bit) != 0 {
+            modifiers |= modifier;
+        }
+    }
+    if mask & 64 != 0 {
+        state |= KeyEventState::CAPS_LOCK;
+    }
+    if mask & 128 != 0 {
+        state |= KeyEventState::NUM_LOCK;
+    }
+    Event::Key(KeyEvent::new_with_kind_and_state(
+        code, modifiers, kind, state,
+    ))
+}
+
+/// Parses the parameters of an SGR mouse report, `CSI < button ; column ; row M/m`
+fn parse_sgr_mouse(parameters: &str, final_byte: u8) -> Option<Event> {
+    let mut fields = parameters.split(';').map(|field| field.parse::<u16>().ok());
+    let button = fields.next()??;
+    let column = fields.next()??.saturating_sub(1);
+    let row = fields.next()??.saturating_sub(1);
+    parse_mouse(button, column, row, final_byte == b'm')
+}
+
+/// Builds a mouse event from an encoded button byte and zero-based position
+fn parse_mouse(button: u16, column: u16, row: u16, release: bool) -> Option<Event> {
+    let mut modifiers = KeyModifiers::NONE;
+    if button & 4 != 0 {
+        modifiers |= KeyModifiers::SHIFT;
+    }
+    if button & 8 != 0 {
+        modifiers |= KeyModifiers::ALT;
+    }
+    if button & 16 != 0 {
+        modifiers |= KeyModifiers::CONTROL;
+    }
+
+    let pressed = match button & 0b11 {
+        0 => Some(MouseButton::Left),
+        1 => Some(MouseButton::Middle),
+        2 => Some(MouseButton::Right),
+        _ => None,
+    };
+    let kind = if button & 64 != 0 {
+        match button & 0b11 {
+            0 => MouseEventKind::ScrollUp,
+            1 => MouseEventKind::ScrollDown,
+            2 => MouseEventKind::ScrollLeft,
+            _ => MouseEventKind::ScrollRight,
+        }
+    } else if button & 32 != 0 {
+        match pressed {
+            Some(pressed) => MouseEventKind::Drag(pressed),
+            None => MouseEventKind::Moved,
+        }
+    } else {
+        match (pressed, release) {
+            (Some(pressed), false) => MouseEventKind::Down(pressed),
+            (Some(pressed), true) => MouseEventKind::Up(pressed),
+            // X10 reports do not say which button was released
+            (None, _) => MouseEventKind::Up(MouseButton::Left),
+        }
+    };
+    Some(Event::Mouse(MouseEvent {
+        kind,
+        column,
+        row,
+        modifiers,
+    }))
+}
+
+fn key(code: KeyCode) -> Event {
+    key_with(code, KeyModifiers::NONE)
+}
+
+fn key_with(code: KeyCode, modifiers: KeyModifiers) -> Event {
+    Event::Key(KeyEvent::new(code, modifiers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses each chunk in turn, as if read separately, with more input expected after every
+    /// chunk but the last
+    fn parse(chunks: &[&[u8]]) -> Vec<Event> {
+        parse_input(chunks)
+            .into_iter()
+            .map(|event| event.event)
+            .collect()
+    }
+
+    /// Parses each chunk as in [`parse`], keeping the details crossterm events do not carry
+    fn parse_input(chunks: &[&[u8]]) -> Vec<InputEvent> {
+        let mut parser = InputParser::default();
+        let mut events = Vec::new();
+        for (index, chunk) in chunks.iter().enumerate() {
+            parser.parse(chunk, index + 1 < chunks.len(), &mut events);
+        }
+        events
+    }
+
+    fn kitty_key(code: KeyCode, modifiers: KeyModifiers, kind: KeyEventKind) -> InputEvent {
+        Event::Key(KeyEvent::new_with_kind(code, modifiers, kind)).into()
+    }
+
+    fn with_base_key(event: InputEvent, base_key: char) -> InputEvent {
+        InputEvent {
+            base_key: Some(KeyCode::Char(base_key)),
+            ..event
+        }
+    }
+
+    fn mouse(kind: MouseEventKind, column: u16, row: u16) -> Event {
+        Event::Mouse(MouseEvent {
+            kind,
+            column,
+            row,
+            modifiers: KeyModifiers::NONE,
+        })
+    }
+
+    #[test]
+    fn legacy_keys() {
+        assert_eq!(parse(&[b"a"]), [key(KeyCode::Char('a'))]);
+        assert_eq!(
+            parse(&[b"A"]),
+            [key_with(KeyCode::Char('A'), KeyModifiers::SHIFT)]
+        );
+        assert_eq!(parse(&["é".as_bytes()]), [key(KeyCode::Char('é'))]);
+        assert_eq!(parse(&[b"\r"]), [key(KeyCode::Enter)]);
+        assert_eq!(parse(&[b"\x7f"]), [key(KeyCode::Backspace)]);
+        assert_eq!(
+            parse(&[b"\x01"]),
+            [key_with(KeyCode::Char('a'), KeyModifiers::CONTROL)]
+        );
+        assert_eq!(
+            parse(&[b"\x1bx"]),
+            [key_with(KeyCode::Char('x'), KeyModifiers::ALT)]
+        );
+        assert_eq!(parse(&[b"\x1b[A"]), [key(KeyCode::Up)]);
+        assert_eq!(
+            parse(&[b"\x1b[1;5C"]),
+            [key_with(KeyCode::Right, KeyModifiers::CONTROL)]
+        );
+        assert_eq!(parse(&[b"\x1b[3~"]), [key(KeyCode::Delete)]);
+        assert_eq!(parse(&[b"\x1b[15~"]), [key(KeyCode::F(5))]);
+        assert_eq!(
+            parse(&[b"\x1b[Z"]),
+            [key_with(KeyCode::BackTab, KeyModifiers::SHIFT)]
+        );
+        assert_eq!(
+            parse(&[b"\x1b[I\x1b[O"]),
+            [Event::FocusGained, Event::FocusLost]
+        );
+    }
+
+    #[test]
+    fn ss3_keys() {
+        assert_eq!(parse(&[b"\x1bOP"]), [key(KeyCode::F(1))]);
+        assert_eq!(parse(&[b"\x1bOA"]), [key(KeyCode::Up)]);
+        assert_eq!(parse(&[b"\x1bOF"]), [key(KeyCode::End)]);
+        // Unknown keys are skipped without swallowing the input after them
+        assert_eq!(parse(&[b"\x1bOzq"]), [key(KeyCode::Char('q'))]);
+    }
+
+    #[test]
+    fn kitty_keys() {
+        use KeyEventKind::{Press, Release, Repeat};
+
+        assert_eq!(
+            parse_input(&[b"\x1b[97u"]),
+            [kitty_key(KeyCode::Char('a'), KeyModifiers::NONE, Press)]
+        );
+        // Shifted keys are reported as the shifted character when shift is held
+        assert_eq!(
+            parse_input(&[b"\x1b[97:65;2u"]),
+            [kitty_key(KeyCode::Char('A'), KeyModifiers::SHIFT, Press)]
+        );
+        assert_eq!(
+            parse_input(&[b"\x1b[49:33;2u"]),
+            [kitty_key(KeyCode::Char('!'), KeyModifiers::SHIFT, Press)]
+        );
+        // Base layout keys are kept alongside the key on the active layout, with or without a
+        // shifted key
+        assert_eq!(
+            parse_input(&[b"\x1b[1092::97;5u"]),
+            [with_base_key(
+                kitty_key(KeyCode::Char('ф'), KeyModifiers::CONTROL, Press),
+                'a'
+            )]
+        );
+        assert_eq!(
+            parse_input(&[b"\x1b[122:90:121;2u"]),
+            [with_base_key(
+                kitty_key(KeyCode::Char('Z'), KeyModifiers::SHIFT, Press),
+                'y'
+            )]
+        );
+
+        // Event types are given after the modifiers
+        assert_eq!(
+            parse_input(&[b"\x1b[97;1:1u\x1b[97;1:2u\x1b[97;1:3u"]),
+            [Press, Repeat, Release].map(|kind| kitty_key(
+                KeyCode::Char('a'),
+                KeyModifiers::NONE,
+                kind
+            ))
+        );
+        assert_eq!(
+            parse(&[b"\x1b[13;3:3u"]),
+            [Event::Key(KeyEvent::new_with_kind(
+                KeyCode::Enter,
+                KeyModifiers::ALT,
+                Release,
+            ))]
+        );
+        // Functional keys which legacy sequences are used for can also report their event type
+        assert_eq!(
+            parse(&[b"\x1b[1;1:3A"]),
+            [Event::Key(KeyEvent::new_with_kind(
+                KeyCode::Up,
+                KeyModifiers::NONE,
+                Release,
+            ))]
+        );
+
+        // Modifier keys always report their own modifier as held, as kitty does while they are
+        // pressed
+        let left_shift = KeyCode::Modifier(ModifierKeyCode::LeftShift);
+        let right_control = KeyCode::Modifier(ModifierKeyCode::RightControl);
+        assert_eq!(
+            parse_input(&[b"\x1b[57441;1u\x1b[57441;1:3u\x1b[57448;5:2u"]),
+            [
+                kitty_key(left_shift, KeyModifiers::SHIFT, Press),
+                kitty_key(left_shift, KeyModifiers::SHIFT, Release),
+                kitty_key(right_control, KeyModifiers::CONTROL, Repeat),
+            ]
+        );
+
+        // Keypad keys are reported as the keys they produce
+        assert_eq!(
+            parse(&[b"\x1b[57400u"]),
+            [Event::Key(KeyEvent::new_with_kind_and_state(
+                KeyCode::Char('1'),
+                KeyModifiers::NONE,
+                Press,
+                KeyEventState::KEYPAD,
+            ))]
+        );
+    }
+
+    #[test]
+    fn sgr_mouse() {
+        assert_eq!(
+            parse(&[b"\x1b[<0;10;5M\x1b[<0;10;5m"]),
+            [
+                mouse(MouseEventKind::Down(MouseButton::Left), 9, 4),
+                mouse(MouseEventKind::Up(MouseButton::Left), 9, 4),
+            ]
+        );
+        assert_eq!(
+            parse(&[b"\x1b[<34;3;4M"]),
+            [mouse(MouseEventKind::Drag(MouseButton::Right), 2, 3)]
+        );
+        assert_eq!(
+            parse(&[b"\x1b[<35;1;1M"]),
+            [mouse(MouseEventKind::Moved, 0, 0)]
+        );
+        assert_eq!(
+            parse(&[b"\x1b[<65;1;1M"]),
+            [mouse(MouseEventKind::ScrollDown, 0, 0)]
+        );
+        assert_eq!(
+            parse(&[b"\x1b[<16;1;1M"]),
+            [Event::Mouse(MouseEvent {
+                kind: MouseEventKind::Down(MouseButton::Left),
+                column: 0,
+                row: 0,
+                modifiers: KeyModifiers::CONTROL,
+            })]
+        );
+    }
+
+    #[test]
+    fn x10_mouse() {
+        assert_eq!(
+            parse(&[b"\x1b[M\x21\x25\x23\x1b[M\x23\x25\x23"]),
+            [
+                mouse(MouseEventKind::Down(MouseButton::Middle), 4, 2),
+                mouse(MouseEventKind::Up(MouseButton::Left), 4, 2),
+            ]
+        );
+        // The report is only complete once all three bytes have arrived
+        assert_eq!(
+            parse(&[b"\x1b[M\x20", b"\x21\x21"]),
+            [mouse(MouseEventKind::Down(MouseButton::Left), 0, 0)]
+        );
+    }
+
+    #[test]
+    fn bracketed_paste_split_across_reads() {
+        let mut parser = InputParser::default();
+        let mut events = Vec::new();
+        parser.parse(b"\x1b[200~hello, ", true, &mut events);
+        assert!(events.is_empty());
+        // The terminating sequence is itself split between reads
+        parser.parse(b"\x1b[world\x1b[20", true, &mut events);
+        assert!(events.is_empty());
+        parser.parse(b"1~x", true, &mut events);
+        let events = events
+            .into_iter()
+            .map(|event| event.event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                Event::Paste("hello, \x1b[world".into()),
+                key(KeyCode::Char('x')),
+            ]
+        );
+    }
+
+    #[test]
+    fn lone_escape() {
+        assert_eq!(parse(&[b"\x1b"]), [key(KeyCode::Esc)]);
+        assert_eq!(
+            parse(&[b"\x1b\x1b[A"]),
+            [key(KeyCode::Esc), key(KeyCode::Up)]
+        );
+        // An escape at the end of a read may be the start of a sequence completed by the next
+        assert_eq!(parse(&[b"\x1b", b"[B"]), [key(KeyCode::Down)]);
+        assert_eq!(
+            parse(&[b"\x1b[", b"1;2", b"D"]),
+            [key_with(KeyCode::Left, KeyModifiers::SHIFT)]
+        );
+    }
+
+    #[test]
+    fn garbage_does_not_panic() {
+        let inputs: [&[u8]; 12] = [
+            b"\x1b[",
+            b"\x1b[M",
+            b"\x1b[<;;M",
+            b"\x1b[<99999999999;1;1M",
+            b"\x1b[99999999999u",
+            b"\x1b[1;abc~",
+            b"\x1b[200",
+            b"\x1bO",
+            b"\xff\xfe\xc3",
+            b"\x1b[:::;:::u",
+            b"\x1b[57399;1:9u",
+            b"\x1b[?1;2c\x1b[?u",
+        ];
+        for input in inputs {
+            parse(&[input]);
+            parse(&[input, b"a"]);
+        }
+
+        // Sequences which never end are dropped once they are too long
+        let mut endless = b"\x1b[".to_vec();
+        endless.extend([b'1'; MAX_SEQUENCE_LENGTH * 2]);
+        assert_eq!(parse(&[&endless, b"a"]), [key(KeyCode::Char('a'))]);
+
+        // Pseudo-random bytes, fed in uneven reads
+        let mut state = 0x2545_F491_u32;
+        let bytes = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                // Favour bytes which start and continue escape sequences
+                match state % 4 {
+                    0 => 0x1B,
+                    1 => b"[O<;:0123456789~uMm"[(state >> 8) as usize % 19],
+                    _ => (state >> 16) as u8,
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut parser = InputParser::default();
+        let mut events = Vec::new();
+        for chunk in bytes.chunks(37) {
+            parser.parse(chunk, true, &mut events);
+        }
+        parser.parse(&[], false, &mut events);
+    }
+}
@@ -1,7 +1,280 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
+use crossbeam_channel::{Receiver, Sender};
 use crossterm::event::Event;
-use std::sync::{Arc, Mutex};
+use std::{fs, io, path::Path};
 
-/// Event queue for crossterm input event thread
-#[derive(Resource, Default)]
-pub(crate) struct EventQueue(pub(super) Arc<Mutex<Vec<Event>>>);
+use super::events::InputArrival;
+
+/// Names of the physical keys which keyboard layout tables can map characters to
+const KEY_NAMES: [(&str, KeyCode); 48] = [
+    ("Backquote", KeyCode::Backquote),
+    ("Digit0", KeyCode::Digit0),
+    ("Digit1", KeyCode::Digit1),
+    ("Digit2", KeyCode::Digit2),
+    ("Digit3", KeyCode::Digit3),
+    ("Digit4", KeyCode::Digit4),
+    ("Digit5", KeyCode::Digit5),
+    ("Digit6", KeyCode::Digit6),
+    ("Digit7", KeyCode::Digit7),
+    ("Digit8", KeyCode::Digit8),
+    ("Digit9", KeyCode::Digit9),
+    ("Minus", KeyCode::Minus),
+    ("Equal", KeyCode::Equal),
+    ("KeyA", KeyCode::KeyA),
+    ("KeyB", KeyCode::KeyB),
+    ("KeyC", KeyCode::KeyC),
+    ("KeyD", KeyCode::KeyD),
+    ("KeyE", KeyCode::KeyE),
+    ("KeyF", KeyCode::KeyF),
+    ("KeyG", KeyCode::KeyG),
+    ("KeyH", KeyCode::KeyH),
+    ("KeyI", KeyCode::KeyI),
+    ("KeyJ", KeyCode::KeyJ),
+    ("KeyK", KeyCode::KeyK),
+    ("KeyL", KeyCode::KeyL),
+    ("KeyM", KeyCode::KeyM),
+    ("KeyN", KeyCode::KeyN),
+    ("KeyO", KeyCode::KeyO),
+    ("KeyP", KeyCode::KeyP),
+    ("KeyQ", KeyCode::KeyQ),
+    ("KeyR", KeyCode::KeyR),
+    ("KeyS", KeyCode::KeyS),
+    ("KeyT", KeyCode::KeyT),
+    ("KeyU", KeyCode::KeyU),
+    ("KeyV", KeyCode::KeyV),
+    ("KeyW", KeyCode::KeyW),
+    ("KeyX", KeyCode::KeyX),
+    ("KeyY", KeyCode::KeyY),
+    ("KeyZ", KeyCode::KeyZ),
+    ("BracketLeft", KeyCode::BracketLeft),
+    ("BracketRight", KeyCode::BracketRight),
+    ("Backslash", KeyCode::Backslash),
+    ("IntlBackslash", KeyCode::IntlBackslash),
+    ("Semicolon", KeyCode::Semicolon),
+    ("Quote", KeyCode::Quote),
+    ("Comma", KeyCode::Comma),
+    ("Period", KeyCode::Period),
+    ("Slash", KeyCode::Slash),
+];
+
+/// Keyboard layout used to find the physical keys of characters, for terminals which only report
+/// the characters typed. Terminals using the kitty keyboard protocol report physical keys
+/// themselves, so the layout is only used for them if they do not.
+///
+/// Layouts are read from tables with a line for each physical key, naming its [`KeyCode`]
+/// followed by every character it types, such as `KeyQ aA` for the key typing `a` in the place of
+/// `Q`. Lines starting with `#` are comments. If a character is listed more than once, the first
+/// key listed is used.
+#[derive(Resource, Debug, Clone, PartialEq, Eq)]
+pub struct KeyboardLayout {
+    keys: HashMap<char, KeyCode>,
+}
+
+impl Default for KeyboardLayout {
+    fn default() -> Self {
+        Self::us()
+    }
+}
+
+impl KeyboardLayout {
+    /// US QWERTY layout
+    pub fn us() -> Self {
+        Self::built_in(include_str!("../../assets/layouts/us.txt"))
+    }
+
+    /// UK QWERTY layout
+    pub fn uk() -> Self {
+        Self::built_in(include_str!("../../assets/layouts/uk.txt"))
+    }
+
+    /// German QWERTZ layout
+    pub fn de() -> Self {
+        Self::built_in(include_str!("../../assets/layouts/de.txt"))
+    }
+
+    /// French AZERTY layout
+    pub fn fr() -> Self {
+        Self::built_in(include_str!("../../assets/layouts/fr.txt"))
+    }
+
+    /// US Dvorak layout
+    pub fn dvorak() -> Self {
+        Self::built_in(include_str!("../../assets/layouts/dvorak.txt"))
+    }
+
+    /// Reads a layout table from a file
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses a layout table, failing if it names a key which is not known
+    pub fn parse(table: &str) -> io::Result<Self> {
+        let mut keys = HashMap::new();
+        for (number, line) in table.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, characters) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let Some(&(_, key_code)) = KEY_NAMES.iter().find(|(known, _)| *known == name) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown key {name:?} on line {}", number + 1),
+                ));
+            };
+            for character in characters
+                .chars()
+                .filter(|character| !character.is_whitespace())
+            {
+                keys.entry(character).or_insert(key_code);
+            }
+        }
+        Ok(Self { keys })
+    }
+
+    /// Physical key typing the given character, if the layout has one. Characters which are not
+    /// listed are looked up in lowercase, such as letters typed with caps lock.
+    pub fn key_code(&self, character: char) -> Option<KeyCode> {
+        if character == ' ' {
+            return Some(KeyCode::Space);
+        }
+        self.keys.get(&character).copied().or_else(|| {
+            let mut lowercase = character.to_lowercase();
+            match (lowercase.next(), lowercase.next()) {
+                (Some(lowercase), None) if lowercase != character => {
+                    self.keys.get(&lowercase).copied()
+                }
+                _ => None,
+            }
+        })
+    }
+
+    fn built_in(table: &str) -> Self {
+        Self::parse(table).expect("Failed to parse built-in keyboard layout")
+    }
+}
+
+/// Input event read from a terminal, along with details which crossterm events do not carry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InputEvent {
+    pub(crate) event: Event,
+    /// For key events, the key at the same position on the base (US) layout, if the terminal
+    /// reported it through the kitty keyboard protocol
+    pub(crate) base_key: Option<crossterm::event::KeyCode>,
+}
+
+impl From<Event> for InputEvent {
+    fn from(event: Event) -> Self {
+        Self {
+            event,
+            base_key: None,
+        }
+    }
+}
+
+/// Event queue filled by the input thread of a terminal output, which events are taken from in
+/// the order they arrived
+#[derive(Clone)]
+pub(crate) struct EventQueue {
+    sender: Sender<(InputEvent, InputArrival)>,
+    receiver: Receiver<(InputEvent, InputArrival)>,
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self { sender, receiver }
+    }
+}
+
+impl EventQueue {
+    /// Adds an event to the queue, marking it as having arrived now
+    pub(crate) fn push(&self, event: impl Into<InputEvent>) {
+        self.sender
+            .send((event.into(), InputArrival::now()))
+            .expect("Failed to push to event queue");
+    }
+
+    /// Takes every event which has arrived so far, oldest first
+    pub(crate) fn drain(&self) -> impl Iterator<Item = (InputEvent, InputArrival)> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use bevy::prelude::KeyCode;
+
+    use super::KeyboardLayout;
+
+    #[test]
+    fn built_in_layouts_parse() {
+        let tables = [
+            include_str!("../../assets/layouts/us.txt"),
+            include_str!("../../assets/layouts/uk.txt"),
+            include_str!("../../assets/layouts/de.txt"),
+            include_str!("../../assets/layouts/fr.txt"),
+            include_str!("../../assets/layouts/dvorak.txt"),
+        ];
+        for table in tables {
+            let layout = KeyboardLayout::parse(table).expect("Failed to parse built-in layout");
+            // Every layout types every letter and digit somewhere
+            for character in ('a'..='z').chain('0'..='9') {
+                assert!(
+                    layout.key_code(character).is_some(),
+                    "{character:?} is missing"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn built_in_layouts_place_keys() {
+        assert_eq!(KeyboardLayout::us().key_code('z'), Some(KeyCode::KeyZ));
+        assert_eq!(KeyboardLayout::uk().key_code('"'), Some(KeyCode::Digit2));
+        assert_eq!(KeyboardLayout::uk().key_code('@'), Some(KeyCode::Quote));
+        assert_eq!(KeyboardLayout::de().key_code('z'), Some(KeyCode::KeyY));
+        assert_eq!(KeyboardLayout::de().key_code('y'), Some(KeyCode::KeyZ));
+        assert_eq!(KeyboardLayout::fr().key_code('a'), Some(KeyCode::KeyQ));
+        assert_eq!(KeyboardLayout::fr().key_code('1'), Some(KeyCode::Digit1));
+        assert_eq!(KeyboardLayout::dvorak().key_code('o'), Some(KeyCode::KeyS));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected_with_line_number() {
+        let error = KeyboardLayout::parse("KeyA a\n\nKeyNope x\n").expect_err("Parsed unknown key");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Unknown key \"KeyNope\" on line 3");
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let layout = KeyboardLayout::parse("# KeyNope is not a key\n\n   \n  KeyA  a A\n")
+            .expect("Failed to parse layout");
+        assert_eq!(layout.key_code('a'), Some(KeyCode::KeyA));
+        assert_eq!(layout.key_code('A'), Some(KeyCode::KeyA));
+        assert_eq!(layout.key_code('#'), None);
+    }
+
+    #[test]
+    fn first_listed_key_wins() {
+        let layout =
+            KeyboardLayout::parse("Digit1 1!\nSlash /!\nKeyA 1").expect("Failed to parse layout");
+        assert_eq!(layout.key_code('1'), Some(KeyCode::Digit1));
+        assert_eq!(layout.key_code('!'), Some(KeyCode::Digit1));
+        assert_eq!(layout.key_code('/'), Some(KeyCode::Slash));
+    }
+
+    #[test]
+    fn unlisted_characters_fall_back_to_lowercase() {
+        let layout = KeyboardLayout::parse("KeyQ q\nKeyE é").expect("Failed to parse layout");
+        // Letters typed with caps lock
+        assert_eq!(layout.key_code('Q'), Some(KeyCode::KeyQ));
+        assert_eq!(layout.key_code('É'), Some(KeyCode::KeyE));
+        assert_eq!(layout.key_code('W'), None);
+        // Space is always the space bar, even though it cannot be listed
+        assert_eq!(layout.key_code(' '), Some(KeyCode::Space));
+    }
+}
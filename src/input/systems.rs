@@ -1,57 +1,641 @@
-use std::cmp::Ordering;
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use bevy::{
-    input::{keyboard::KeyboardInput, ButtonState},
+    ecs::system::SystemParam,
+    input::{
+        keyboard::{KeyboardFocusLost, KeyboardInput},
+        mouse::{MouseButtonInput, MouseMotion, MouseScrollUnit, MouseWheel},
+        ButtonState,
+    },
     prelude::*,
+    utils::{HashMap, HashSet},
+    window::{CursorEntered, CursorLeft, WindowFocused, WindowResized},
+};
+use crossterm::event::{
+    read, Event, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers, MediaKeyCode,
+    ModifierKeyCode, MouseEvent, MouseEventKind,
 };
-use crossterm::event::{read, Event, KeyEvent, KeyEventKind, MediaKeyCode, ModifierKeyCode};
+use once_cell::sync::Lazy;
 use smol_str::SmolStr;
 
+use crate::{display::resources::Terminal, tty::Tty, KeyReleaseTimeout};
+
 use super::{
-    components::DummyWindow,
+    components::{TerminalCursor, PIXELS_PER_CELL},
     events::TerminalInputEvent,
-    resources::EventQueue,
+    parser::InputParser,
+    resources::{EventQueue, InputEvent, KeyboardLayout},
 };
 
-/// Initializes event queue and thread
-pub fn setup_input(mut commands: Commands, event_queue: Res<EventQueue>) {
-    commands.spawn(DummyWindow);
-    let event_queue = event_queue.0.clone();
+/// Layout which the kitty keyboard protocol reports base layout keys in
+static BASE_LAYOUT: Lazy<KeyboardLayout> = Lazy::new(KeyboardLayout::us);
+
+/// How often the size of a terminal device is checked, since resizes are only signalled for the
+/// controlling terminal, and only to its foreground process
+const DEVICE_RESIZE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether a thread reading input from the controlling terminal has been started
+static CONTROLLING_INPUT_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts a thread reading input from the given terminal device, or from the controlling terminal
+/// if there is none, and returns the queue it pushes events to. Only one thread can read from the
+/// controlling terminal, so the queues of any further terminals using it stay empty.
+///
+/// The controlling terminal is read with crossterm, unless the kitty keyboard protocol has been
+/// enabled on it, since crossterm drops the base layout keys the protocol reports. Its device is
+/// then read directly instead, where possible.
+///
+/// `pending` is input which was read from the terminal before the thread started, such as while
+/// it was being queried, and is parsed before anything else.
+#[cfg_attr(not(unix), allow(unused_variables))]
+pub(crate) fn spawn_input_thread(
+    device: Option<Arc<Tty>>,
+    keyboard_enhancement: bool,
+    pending: Vec<u8>,
+) -> EventQueue {
+    let event_queue = EventQueue::default();
+    let queue = event_queue.clone();
+    if let Some(device) = device {
+        std::thread::spawn(move || read_device_input(&device, &queue, &pending));
+        return event_queue;
+    }
+    if CONTROLLING_INPUT_STARTED.swap(true, Ordering::SeqCst) {
+        warn!("Input from the controlling terminal is already read by another terminal output");
+        return event_queue;
+    }
+    #[cfg(unix)]
+    if keyboard_enhancement {
+        match Tty::open(std::path::Path::new("/dev/tty")) {
+            Ok(device) => {
+                std::thread::spawn(move || read_device_input(&device, &queue, &pending));
+                return event_queue;
+            }
+            Err(error) => warn!("Failed to open /dev/tty, base layout keys are ignored: {error}"),
+        }
+    }
+    // Crossterm cannot be given input to parse, so pending input is parsed here instead
+    let mut events = Vec::new();
+    InputParser::default().parse(&pending, false, &mut events);
+    for event in events {
+        queue.push(event);
+    }
     std::thread::spawn(move || {
         loop {
             // `read()` blocks until an `Event` is available
             match read() {
                 Ok(event) => {
-                    event_queue.lock().unwrap().push(event);
+                    queue.push(event);
                 }
                 Err(err) => {
-                    panic!("Error reading input events: {:?}", err);
+                    // There may be no controlling terminal, such as when running headless
+                    error!("Error reading input events: {:?}", err);
+                    return;
                 }
             }
         }
     });
+    event_queue
 }
 
-/// Reads events from queue and broadcasts corresponding `TerminalInputEvent`s
+/// Reads and parses input from a terminal device, following on from the given pending input, and
+/// watches for changes to its size
+fn read_device_input(device: &Tty, event_queue: &EventQueue, pending: &[u8]) {
+    let mut reader = match device.reader() {
+        Ok(reader) => reader,
+        Err(error) => {
+            error!("Failed to open terminal device for input: {error}");
+            return;
+        }
+    };
+    let mut parser = InputParser::default();
+    let mut size = device.size().ok();
+    let mut chunk = [0; 1024];
+    let mut events = Vec::new();
+    // Sequences cut off at the end of the pending input are completed by the next read
+    parser.parse(pending, true, &mut events);
+    for event in events.drain(..) {
+        event_queue.push(event);
+    }
+    loop {
+        let ready = match device.poll_input(DEVICE_RESIZE_POLL_INTERVAL) {
+            Ok(ready) => ready,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => false,
+            Err(error) => {
+                error!("Error polling terminal device: {error}");
+                return;
+            }
+        };
+        if ready {
+            match reader.read(&mut chunk) {
+                // The other end of the device has been closed
+                Ok(0) => return,
+                Ok(read) => parser.parse(&chunk[..read], read == chunk.len(), &mut events),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => {
+                    error!("Error reading input events: {:?}", error);
+                    return;
+                }
+            }
+        }
+
+        let new_size = device.size().ok();
+        if new_size != size {
+            if let Some((width, height)) = new_size {
+                events.push(Event::Resize(width, height).into());
+            }
+            size = new_size;
+        }
+
+        for event in events.drain(..) {
+            event_queue.push(event);
+        }
+    }
+}
+
+/// Writers for the Bevy mouse events sent for terminal mouse input
+#[derive(SystemParam)]
+pub struct MouseEventWriters<'w> {
+    buttons: EventWriter<'w, MouseButtonInput>,
+    cursor_moved: EventWriter<'w, CursorMoved>,
+    wheel: EventWriter<'w, MouseWheel>,
+    motion: EventWriter<'w, MouseMotion>,
+}
+
+/// Writers for the Bevy events sent for changes to the window of a terminal, including the loss
+/// of keyboard focus
+#[derive(SystemParam)]
+pub struct WindowEventWriters<'w> {
+    resized: EventWriter<'w, WindowResized>,
+    focused: EventWriter<'w, WindowFocused>,
+    cursor_entered: EventWriter<'w, CursorEntered>,
+    cursor_left: EventWriter<'w, CursorLeft>,
+    keyboard_focus_lost: EventWriter<'w, KeyboardFocusLost>,
+}
+
+/// Key event along with the physical key it was resolved to
+#[derive(Clone, Copy)]
+struct PhysicalKeyEvent {
+    event: KeyEvent,
+    key_code: Option<KeyCode>,
+}
+
+impl PhysicalKeyEvent {
+    /// Resolves the physical key of a key event. Keypad keys are told apart by their state, and
+    /// other keys are looked up by the key at the same position on the base layout if the
+    /// terminal reported it, or in the given layout otherwise.
+    fn new(
+        event: KeyEvent,
+        base_key: Option<crossterm::event::KeyCode>,
+        layout: &KeyboardLayout,
+    ) -> Self {
+        let keypad_key = event
+            .state
+            .contains(KeyEventState::KEYPAD)
+            .then(|| crossterm_keypad_code_to_bevy_keycode(event.code))
+            .flatten();
+        let key_code = keypad_key.or_else(|| match base_key {
+            Some(base_key) => crossterm_keycode_to_bevy_keycode(base_key, &BASE_LAYOUT),
+            None => crossterm_keycode_to_bevy_keycode(event.code, layout),
+        });
+        Self { event, key_code }
+    }
+}
+
+/// Key held in a terminal which key releases are synthesised for
+struct HeldKey {
+    /// Event the key was last pressed or repeated with
+    event: PhysicalKeyEvent,
+    /// Time at which the key is released unless it is repeated
+    release_at: Duration,
+}
+
+/// Modifiers which are tracked from the flags of key events, and the modifier keys they are
+/// pressed and released as
+const MODIFIER_KEYS: [(KeyModifiers, ModifierKeyCode); 4] = [
+    (KeyModifiers::SHIFT, ModifierKeyCode::LeftShift),
+    (KeyModifiers::CONTROL, ModifierKeyCode::LeftControl),
+    (KeyModifiers::ALT, ModifierKeyCode::LeftAlt),
+    (KeyModifiers::SUPER, ModifierKeyCode::LeftSuper),
+];
+
+/// Key and mouse button state of a terminal output, carried between runs of [`input_handling`]
+#[derive(Default)]
+pub struct InputState {
+    /// Keys held, if key releases are synthesised for the terminal
+    held_keys: HashMap<KeyCode, HeldKey>,
+    /// Key holding each of the [`MODIFIER_KEYS`], according to the last key event
+    held_modifiers: [Option<ModifierKeyCode>; MODIFIER_KEYS.len()],
+    /// Every key which has been sent as pressed and not yet released
+    pressed_keys: HashMap<KeyCode, PhysicalKeyEvent>,
+    /// Every mouse button which has been sent as pressed and not yet released, which must be
+    /// released before [`Self::release_all`] resets the state
+    pressed_buttons: HashSet<MouseButton>,
+}
+
+impl InputState {
+    /// Completes the given key events, which must be in the order they were received, with
+    /// synthesised key releases if there is a timeout for them and with modifier key presses and
+    /// releases, and keeps track of the keys they leave pressed
+    fn process(
+        &mut self,
+        mut events: Vec<PhysicalKeyEvent>,
+        now: Duration,
+        release_timeout: Option<KeyReleaseTimeout>,
+    ) -> Vec<PhysicalKeyEvent> {
+        if let Some(timeout) = release_timeout {
+            events = self.emulate_releases(events, now, timeout);
+        }
+        let events = self.track_modifiers(events);
+        for key in &events {
+            if let Some(key_code) = key.key_code {
+                if key.event.kind == KeyEventKind::Release {
+                    self.pressed_keys.remove(&key_code);
+                } else {
+                    self.pressed_keys.insert(key_code, *key);
+                }
+            }
+        }
+        events
+    }
+
+    /// Releases every pressed key and forgets everything held, for when releases will not be
+    /// reported. Modifier keys are released after the others.
+    fn release_all(&mut self) -> Vec<PhysicalKeyEvent> {
+        let mut releases = std::mem::take(&mut self.pressed_keys)
+            .into_values()
+            .map(|mut release| {
+                release.event.kind = KeyEventKind::Release;
+                release.event.modifiers = KeyModifiers::NONE;
+                release
+            })
+            .collect::<Vec<_>>();
+        releases.sort_by_key(|release| {
+            matches!(release.event.code, crossterm::event::KeyCode::Modifier(_))
+        });
+        *self = default();
+        releases
+    }
+
+    /// Turns presses of keys which are already held into repeats, and adds releases for keys
+    /// which have not been repeated within the timeout. Modifiers are released along with the
+    /// last held key.
+    fn emulate_releases(
+        &mut self,
+        events: Vec<PhysicalKeyEvent>,
+        now: Duration,
+        timeout: KeyReleaseTimeout,
+    ) -> Vec<PhysicalKeyEvent> {
+        let mut emulated = Vec::with_capacity(events.len());
+        for mut key in events {
+            if let Some(key_code) = key.key_code {
+                if key.event.kind == KeyEventKind::Release {
+                    self.held_keys.remove(&key_code);
+                } else {
+                    let (kind, hold) = if self.held_keys.contains_key(&key_code) {
+                        (KeyEventKind::Repeat, timeout.after_repeat)
+                    } else {
+                        (KeyEventKind::Press, timeout.after_press)
+                    };
+                    key.event.kind = kind;
+                    let release_at = now + hold;
+                    self.held_keys.insert(
+                        key_code,
+                        HeldKey {
+                            event: key,
+                            release_at,
+                        },
+                    );
+                }
+            }
+            emulated.push(key);
+        }
+        let mut releases = Vec::new();
+        self.held_keys.retain(|_, held| {
+            let held_on = held.release_at > now;
+            if !held_on {
+                let mut release = held.event;
+                release.event.kind = KeyEventKind::Release;
+                releases.push(release);
+            }
+            held_on
+        });
+        if self.held_keys.is_empty() {
+            for release in &mut releases {
+                release.event.modifiers = KeyModifiers::NONE;
+            }
+        }
+        emulated.append(&mut releases);
+        emulated
+    }
+
+    /// Adds presses and releases of modifier keys wherever the modifiers of the given key events,
+    /// which must be in the order they were received, change. Terminals only report modifier
+    /// keys themselves when all keys are reported with keyboard enhancement, in which case they
+    /// are tracked from those instead.
+    fn track_modifiers(&mut self, events: Vec<PhysicalKeyEvent>) -> Vec<PhysicalKeyEvent> {
+        let mut tracked = Vec::with_capacity(events.len());
+        for key in events {
+            let event = key.event;
+            if let crossterm::event::KeyCode::Modifier(modifier_key) = event.code {
+                let tracked_modifier = modifier_of_key(modifier_key).and_then(|modifier| {
+                    MODIFIER_KEYS
+                        .iter()
+                        .position(|(tracked, _)| *tracked == modifier)
+                });
+                if let Some(index) = tracked_modifier {
+                    self.held_modifiers[index] =
+                        (event.kind != KeyEventKind::Release).then_some(modifier_key);
+                }
+                tracked.push(key);
+                continue;
+            }
+            // Modifiers let go along with a key are released after it
+            let release_after = event.kind == KeyEventKind::Release;
+            let mut released = Vec::new();
+            for (held, (modifier, modifier_key)) in
+                self.held_modifiers.iter_mut().zip(MODIFIER_KEYS)
+            {
+                let (kind, modifier_key) = match (event.modifiers.contains(modifier), *held) {
+                    (true, None) => (KeyEventKind::Press, modifier_key),
+                    (false, Some(held_key)) => (KeyEventKind::Release, held_key),
+                    _ => continue,
+                };
+                *held = (kind == KeyEventKind::Press).then_some(modifier_key);
+                let modifier_event = KeyEvent::new_with_kind(
+                    crossterm::event::KeyCode::Modifier(modifier_key),
+                    event.modifiers,
+                    kind,
+                );
+                // Modifier keys are in the same place on every layout
+                let modifier_event = PhysicalKeyEvent::new(modifier_event, None, &BASE_LAYOUT);
+                if kind == KeyEventKind::Release && release_after {
+                    released.push(modifier_event);
+                } else {
+                    tracked.push(modifier_event);
+                }
+            }
+            tracked.push(key);
+            tracked.append(&mut released);
+        }
+        tracked
+    }
+}
+
+/// Modifier flag set while the given modifier key is held, if it is tracked
+fn modifier_of_key(key: ModifierKeyCode) -> Option<KeyModifiers> {
+    match key {
+        ModifierKeyCode::LeftShift | ModifierKeyCode::RightShift => Some(KeyModifiers::SHIFT),
+        ModifierKeyCode::LeftControl | ModifierKeyCode::RightControl => Some(KeyModifiers::CONTROL),
+        ModifierKeyCode::LeftAlt | ModifierKeyCode::RightAlt => Some(KeyModifiers::ALT),
+        ModifierKeyCode::LeftSuper | ModifierKeyCode::RightSuper => Some(KeyModifiers::SUPER),
+        _ => None,
+    }
+}
+
+/// Reads events from the queue of each terminal output and broadcasts corresponding
+/// `TerminalInputEvent`s. Keyboard and mouse input is attributed to the terminal output entity it
+/// was received from, and moves its [`TerminalCursor`]. Physical keys are found with the
+/// [`KeyboardLayout`] unless the terminal reports them. For terminals which do not report key
+/// releases, releases are synthesised as described in
+/// [`KeyReleaseEmulation`](crate::KeyReleaseEmulation). Modifiers are tracked from the flags of
+/// key events, and sent as presses and releases of the left modifier keys when they change.
+/// Every pressed key and mouse button is released when the terminal loses focus.
+///
+/// Terminals are handled one after another, and each terminal's key events are sent after its
+/// mouse events, so the Bevy input events of a frame are not in arrival order. Consumers which
+/// need the order of input across terminals or between keys and the mouse should sort
+/// `TerminalInputEvent`s by their [`InputArrival`](super::events::InputArrival) instead.
 pub fn input_handling(
-    event_queue: Res<EventQueue>,
-    dummy_window_query: Query<Entity, With<DummyWindow>>,
+    mut terminals: Query<(Entity, &Terminal, &mut TerminalCursor, Has<Window>)>,
+    layout: Res<KeyboardLayout>,
+    time: Res<Time<Real>>,
     mut terminal_event_writer: EventWriter<TerminalInputEvent>,
     mut key_event_writer: EventWriter<KeyboardInput>,
+    mut mouse_event_writers: MouseEventWriters,
+    mut input_states: Local<HashMap<Entity, InputState>>,
 ) {
-    let mut event_queue = event_queue.0.lock().unwrap();
-    let mut key_events = Vec::<KeyEvent>::new();
-    while let Some(event) = event_queue.pop() {
-        if let Event::Key(event) = event {
-            key_events.push(event);
+    input_states.retain(|entity, _| terminals.contains(*entity));
+    for (window, terminal, mut cursor, has_window) in terminals.iter_mut() {
+        let input_state = input_states.entry(window).or_default();
+        let release_timeout = terminal.key_release_timeout();
+        let mut key_events = Vec::<PhysicalKeyEvent>::new();
+        let mut received_keys = Vec::<PhysicalKeyEvent>::new();
+        for (InputEvent { event, base_key }, arrival) in terminal.input_queue().drain() {
+            match event {
+                Event::Key(event) => {
+                    received_keys.push(PhysicalKeyEvent::new(event, base_key, &layout));
+                }
+                Event::Mouse(event) if has_window => send_mouse_event(
+                    event,
+                    window,
+                    &mut cursor,
+                    &mut input_state.pressed_buttons,
+                    &mut mouse_event_writers,
+                ),
+                // Bevy's mouse events are sent for windows, so terminals without one only track
+                // the cursor
+                Event::Mouse(event) => {
+                    cursor.move_to(UVec2::new(event.column.into(), event.row.into()));
+                }
+                // Everything held is released when focus is lost, since the releases of keys and
+                // buttons let go elsewhere are not reported
+                Event::FocusLost => {
+                    let received_keys = std::mem::take(&mut received_keys);
+                    key_events.append(&mut input_state.process(
+                        received_keys,
+                        time.elapsed(),
+                        release_timeout,
+                    ));
+                    for button in input_state.pressed_buttons.drain() {
+                        mouse_event_writers.buttons.send(MouseButtonInput {
+                            button,
+                            state: ButtonState::Released,
+                            window,
+                        });
+                    }
+                    key_events.append(&mut input_state.release_all());
+                }
+                _ => {}
+            }
+            terminal_event_writer.send(TerminalInputEvent {
+                event,
+                terminal: window,
+                arrival,
+            });
         }
-        terminal_event_writer.send(TerminalInputEvent(event));
+
+        key_events.append(&mut input_state.process(received_keys, time.elapsed(), release_timeout));
+        send_key_events(key_events, window, &mut key_event_writer);
     }
+}
 
-    key_events.sort_by(|&a, &b| a.kind.partial_cmp(&b.kind).unwrap_or(Ordering::Equal));
-    let window = dummy_window_query.single();
-    for event in key_events {
-        if let Some(key_code) = crossterm_keycode_to_bevy_keycode(event.code) {
+/// Sends Bevy mouse input events for the given crossterm mouse event. Cursor positions are
+/// reported in display pixels, at the centre of the cell the cursor is over.
+fn send_mouse_event(
+    event: MouseEvent,
+    window: Entity,
+    cursor: &mut TerminalCursor,
+    pressed_buttons: &mut HashSet<MouseButton>,
+    writers: &mut MouseEventWriters,
+) {
+    let previous = cursor.position;
+    if cursor.move_to(UVec2::new(event.column.into(), event.row.into())) {
+        let position = cursor.position.expect("Cursor position was not set");
+        let delta = previous.map(|previous| position - previous);
+        writers.cursor_moved.send(CursorMoved {
+            window,
+            position,
+            delta,
+        });
+        if let Some(delta) = delta {
+            writers.motion.send(MouseMotion { delta });
+        }
+    }
+
+    let (x, y) = match event.kind {
+        MouseEventKind::Down(button) | MouseEventKind::Up(button) => {
+            let button = crossterm_button_to_bevy_button(button);
+            let state = match event.kind {
+                MouseEventKind::Down(_) => {
+                    pressed_buttons.insert(button);
+                    ButtonState::Pressed
+                }
+                _ => {
+                    pressed_buttons.remove(&button);
+                    ButtonState::Released
+                }
+            };
+            writers.buttons.send(MouseButtonInput {
+                button,
+                state,
+                window,
+            });
+            return;
+        }
+        MouseEventKind::Moved | MouseEventKind::Drag(_) => return,
+        MouseEventKind::ScrollUp => (0.0, 1.0),
+        MouseEventKind::ScrollDown => (0.0, -1.0),
+        MouseEventKind::ScrollLeft => (-1.0, 0.0),
+        MouseEventKind::ScrollRight => (1.0, 0.0),
+    };
+    writers.wheel.send(MouseWheel {
+        unit: MouseScrollUnit::Line,
+        x,
+        y,
+        window,
+    });
+}
+
+/// Keeps the [`Window`] of each terminal output which has one in sync with the terminal, so that
+/// systems using windows work unchanged. The resolution is the size of the terminal's viewport in
+/// display pixels, the cursor follows its [`TerminalCursor`], and it is focused unless focus
+/// reporting says otherwise. Terminals do not report the cursor leaving them, so it is treated as
+/// leaving when focus is lost.
+///
+/// Releases are not reported for keys and mouse buttons let go while a terminal is not focused,
+/// so everything held is released when any terminal loses focus, whether it has a window or not.
+#[allow(clippy::type_complexity)]
+pub fn window_handling(
+    mut terminals: Query<(Entity, &Terminal, Ref<TerminalCursor>, Option<&mut Window>)>,
+    mut event_reader: EventReader<TerminalInputEvent>,
+    mut writers: WindowEventWriters,
+) {
+    let events = event_reader.read().collect::<Vec<_>>();
+    for (entity, terminal, cursor, window) in terminals.iter_mut() {
+        if let Some(window) = window {
+            sync_window(entity, terminal, &cursor, window, &events, &mut writers);
+        }
+
+        let focus_lost = events
+            .iter()
+            .any(|event| event.terminal == entity && matches!(event.event, Event::FocusLost));
+        if focus_lost {
+            writers.keyboard_focus_lost.send(KeyboardFocusLost);
+        }
+    }
+}
+
+/// Updates the window of a terminal output from the events received from it and its cursor
+fn sync_window(
+    entity: Entity,
+    terminal: &Terminal,
+    cursor: &Ref<TerminalCursor>,
+    mut window: Mut<Window>,
+    events: &[&TerminalInputEvent],
+    writers: &mut WindowEventWriters,
+) {
+    let resized = events
+        .iter()
+        .any(|event| event.terminal == entity && matches!(event.event, Event::Resize(..)));
+    if resized || window.is_added() {
+        if let Ok(area) = terminal.area() {
+            let size =
+                UVec2::new(area.width.into(), area.height.into()).as_vec2() * PIXELS_PER_CELL;
+            window
+                .resolution
+                .set_physical_resolution(size.x as u32, size.y as u32);
+            writers.resized.send(WindowResized {
+                window: entity,
+                width: window.width(),
+                height: window.height(),
+            });
+        }
+    }
+
+    for event in events.iter().filter(|event| event.terminal == entity) {
+        let focused = match event.event {
+            Event::FocusGained => true,
+            Event::FocusLost => false,
+            _ => continue,
+        };
+        if window.focused != focused {
+            window.focused = focused;
+            writers.focused.send(WindowFocused {
+                window: entity,
+                focused,
+            });
+        }
+        if !focused && window.cursor_position().is_some() {
+            window.set_cursor_position(None);
+            writers.cursor_left.send(CursorLeft { window: entity });
+        }
+    }
+
+    // The cursor is changed by every mouse event, even if it stays in the same cell
+    if cursor.is_changed() && !cursor.is_added() {
+        if window.cursor_position().is_none() {
+            writers
+                .cursor_entered
+                .send(CursorEntered { window: entity });
+        }
+        let position = cursor.position.map(|position| position.as_dvec2());
+        window.set_physical_cursor_position(position);
+    }
+}
+
+fn crossterm_button_to_bevy_button(button: crossterm::event::MouseButton) -> MouseButton {
+    match button {
+        crossterm::event::MouseButton::Left => MouseButton::Left,
+        crossterm::event::MouseButton::Right => MouseButton::Right,
+        crossterm::event::MouseButton::Middle => MouseButton::Middle,
+    }
+}
+
+/// Sends Bevy keyboard input events for the given crossterm key events
+fn send_key_events(
+    key_events: Vec<PhysicalKeyEvent>,
+    window: Entity,
+    key_event_writer: &mut EventWriter<KeyboardInput>,
+) {
+    for PhysicalKeyEvent { event, key_code } in key_events {
+        if let Some(key_code) = key_code {
             if let Some(logical_key) = crossterm_keycode_to_bevy_key(event.code) {
                 match event.kind {
                     KeyEventKind::Press => {
@@ -89,6 +673,7 @@ pub fn input_handling(
 
 fn crossterm_keycode_to_bevy_keycode(
     crossterm_keycode: crossterm::event::KeyCode,
+    layout: &KeyboardLayout,
 ) -> Option<bevy::input::keyboard::KeyCode> {
     use bevy::input::keyboard::KeyCode as BKey;
     use crossterm::event::KeyCode as CKey;
@@ -144,56 +729,7 @@ fn crossterm_keycode_to_bevy_keycode(
             35 => Some(BKey::F35),
             _ => None,
         },
-        CKey::Char(c) => match c {
-            '1' | '!' => Some(BKey::Digit1),
-            '2' | '@' => Some(BKey::Digit2),
-            '3' | '#' => Some(BKey::Digit3),
-            '4' | '$' => Some(BKey::Digit4),
-            '5' | '%' => Some(BKey::Digit5),
-            '6' | '^' => Some(BKey::Digit5),
-            '7' | '&' => Some(BKey::Digit7),
-            '8' | '*' => Some(BKey::Digit8),
-            '9' | '(' => Some(BKey::Digit9),
-            '0' | ')' => Some(BKey::Digit0),
-            '-' | '_' => Some(BKey::Minus),
-            '=' | '+' => Some(BKey::Equal),
-            '`' | '~' => Some(BKey::Backquote),
-            'q' | 'Q' => Some(BKey::KeyQ),
-            'w' | 'W' => Some(BKey::KeyW),
-            'e' | 'E' => Some(BKey::KeyE),
-            'r' | 'R' => Some(BKey::KeyR),
-            't' | 'T' => Some(BKey::KeyT),
-            'y' | 'Y' => Some(BKey::KeyY),
-            'u' | 'U' => Some(BKey::KeyU),
-            'i' | 'I' => Some(BKey::KeyI),
-            'o' | 'O' => Some(BKey::KeyO),
-            'p' | 'P' => Some(BKey::KeyP),
-            '[' | '{' => Some(BKey::BracketLeft),
-            ']' | '}' => Some(BKey::BracketRight),
-            'a' | 'A' => Some(BKey::KeyA),
-            's' | 'S' => Some(BKey::KeyS),
-            'd' | 'D' => Some(BKey::KeyD),
-            'f' | 'F' => Some(BKey::KeyF),
-            'g' | 'G' => Some(BKey::KeyG),
-            'h' | 'H' => Some(BKey::KeyH),
-            'j' | 'J' => Some(BKey::KeyJ),
-            'k' | 'K' => Some(BKey::KeyK),
-            'l' | 'L' => Some(BKey::KeyL),
-            ';' | ':' => Some(BKey::Semicolon),
-            '\'' | '"' => Some(BKey::Slash),
-            'z' | 'Z' => Some(BKey::KeyZ),
-            'x' | 'X' => Some(BKey::KeyX),
-            'c' | 'C' => Some(BKey::KeyC),
-            'v' | 'V' => Some(BKey::KeyV),
-            'b' | 'B' => Some(BKey::KeyB),
-            'n' | 'N' => Some(BKey::KeyN),
-            'm' | 'M' => Some(BKey::KeyM),
-            ',' | '<' => Some(BKey::Comma),
-            '.' | '>' => Some(BKey::Period),
-            '/' | '?' => Some(BKey::Slash),
-            ' ' => Some(BKey::Space),
-            _ => None,
-        },
+        CKey::Char(c) => layout.key_code(c),
         CKey::Null => None,
         CKey::Esc => Some(BKey::Escape),
         CKey::CapsLock => Some(BKey::CapsLock),
@@ -227,7 +763,7 @@ fn crossterm_keycode_to_bevy_keycode(
             ModifierKeyCode::LeftMeta => Some(BKey::Meta),
             ModifierKeyCode::RightShift => Some(BKey::ShiftRight),
             ModifierKeyCode::RightControl => Some(BKey::ControlRight),
-            ModifierKeyCode::RightAlt => Some(BKey::AltLeft),
+            ModifierKeyCode::RightAlt => Some(BKey::AltRight),
             ModifierKeyCode::RightSuper => Some(BKey::SuperRight),
             ModifierKeyCode::RightHyper => Some(BKey::Hyper),
             ModifierKeyCode::RightMeta => Some(BKey::Meta),
@@ -237,6 +773,36 @@ fn crossterm_keycode_to_bevy_keycode(
     }
 }
 
+/// Maps a key code of a keypad key to the Bevy key code of the keypad key. Keys which Bevy does
+/// not distinguish from the main keys, such as the keypad arrows, map to `None`.
+fn crossterm_keypad_code_to_bevy_keycode(
+    crossterm_keycode: crossterm::event::KeyCode,
+) -> Option<bevy::input::keyboard::KeyCode> {
+    use bevy::input::keyboard::KeyCode as BKey;
+    use crossterm::event::KeyCode as CKey;
+    match crossterm_keycode {
+        CKey::Char('0') => Some(BKey::Numpad0),
+        CKey::Char('1') => Some(BKey::Numpad1),
+        CKey::Char('2') => Some(BKey::Numpad2),
+        CKey::Char('3') => Some(BKey::Numpad3),
+        CKey::Char('4') => Some(BKey::Numpad4),
+        CKey::Char('5') => Some(BKey::Numpad5),
+        CKey::Char('6') => Some(BKey::Numpad6),
+        CKey::Char('7') => Some(BKey::Numpad7),
+        CKey::Char('8') => Some(BKey::Numpad8),
+        CKey::Char('9') => Some(BKey::Numpad9),
+        CKey::Char('.') => Some(BKey::NumpadDecimal),
+        CKey::Char('/') => Some(BKey::NumpadDivide),
+        CKey::Char('*') => Some(BKey::NumpadMultiply),
+        CKey::Char('-') => Some(BKey::NumpadSubtract),
+        CKey::Char('+') => Some(BKey::NumpadAdd),
+        CKey::Char('=') => Some(BKey::NumpadEqual),
+        CKey::Char(',') => Some(BKey::NumpadComma),
+        CKey::Enter => Some(BKey::NumpadEnter),
+        _ => None,
+    }
+}
+
 fn crossterm_keycode_to_bevy_key(
     crossterm_keycode: crossterm::event::KeyCode,
 ) -> Option<bevy::input::keyboard::Key> {
@@ -294,7 +860,7 @@ fn crossterm_keycode_to_bevy_key(
             35 => Some(BKey::F35),
             _ => None,
         },
-        CKey::Char(c) => Some(BKey::Character(SmolStr::from(c.encode_utf8(&mut [0;4])))),
+        CKey::Char(c) => Some(BKey::Character(SmolStr::from(c.encode_utf8(&mut [0; 4])))),
         CKey::Null => None,
         CKey::Esc => Some(BKey::Escape),
         CKey::CapsLock => Some(BKey::CapsLock),
@@ -337,3 +903,416 @@ fn crossterm_keycode_to_bevy_key(
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::{
+        input::{keyboard::KeyboardInput, mouse::MouseButtonInput, ButtonState, InputPlugin},
+        prelude::*,
+        window::PrimaryWindow,
+    };
+    use crossterm::event::{
+        Event, KeyCode as TermKeyCode, KeyEvent, KeyEventKind, KeyModifiers, ModifierKeyCode,
+        MouseButton as TermMouseButton, MouseEvent, MouseEventKind,
+    };
+    use ratatui::layout::Size;
+
+    use crate::{
+        display::resources::{PrimaryTerminal, Terminal},
+        input::{components::TerminalCursor, events::TerminalInputEvent},
+        testing::snapshot::SnapshotApp,
+        KeyReleaseEmulation, KeyReleaseTimeout, TerminalDisplayPlugin, TerminalSettings,
+        TerminalTarget,
+    };
+
+    /// Time between the frames of [`key_input`]
+    const FRAME_TIME: Duration = Duration::from_millis(50);
+
+    /// App with Bevy's input handling and a headless primary terminal of the given size
+    fn input_app(size: Size, settings: TerminalSettings) -> SnapshotApp {
+        SnapshotApp::with_plugins((
+            MinimalPlugins,
+            InputPlugin,
+            TerminalDisplayPlugin {
+                target: TerminalTarget::Headless(size),
+                log_path: std::env::temp_dir().join("bevy_terminal_display_snapshot.log"),
+                settings,
+                ..default()
+            },
+        ))
+    }
+
+    /// Keys pressed and released in each frame, with the given events received in each and
+    /// frames [`FRAME_TIME`] apart
+    fn key_input(
+        settings: TerminalSettings,
+        frames: Vec<Vec<Event>>,
+    ) -> Vec<Vec<(KeyCode, ButtonState)>> {
+        let mut app = input_app(Size::new(4, 1), settings).with_timestep(FRAME_TIME);
+        app.step(1);
+        frames
+            .into_iter()
+            .map(|events| {
+                let world = app.app_mut().world_mut();
+                let primary = world.resource::<PrimaryTerminal>().0;
+                let terminal = world
+                    .get::<Terminal>(primary)
+                    .expect("Primary terminal does not exist");
+                for event in events {
+                    terminal.inject_input(event);
+                }
+                app.step(1);
+                app.app_mut()
+                    .world_mut()
+                    .resource_mut::<Events<KeyboardInput>>()
+                    .drain()
+                    .map(|event| (event.key_code, event.state))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn focus_loss_releases_pressed_keys() {
+        let shift_a = Event::Key(KeyEvent::new_with_kind(
+            TermKeyCode::Char('A'),
+            KeyModifiers::SHIFT,
+            KeyEventKind::Press,
+        ));
+        let pressed = vec![
+            (KeyCode::ShiftLeft, ButtonState::Pressed),
+            (KeyCode::KeyA, ButtonState::Pressed),
+        ];
+        let released = vec![
+            (KeyCode::KeyA, ButtonState::Released),
+            (KeyCode::ShiftLeft, ButtonState::Released),
+        ];
+        for key_release_emulation in [KeyReleaseEmulation::Never, KeyReleaseEmulation::Always] {
+            let settings = TerminalSettings {
+                key_release_emulation,
+                ..default()
+            };
+            let input = key_input(
+                settings,
+                vec![
+                    vec![shift_a.clone()],
+                    vec![Event::FocusLost],
+                    // Keys pressed just before focus is lost are released after their presses
+                    vec![shift_a.clone(), Event::FocusLost],
+                    // Keys pressed after focus is regained are pressed anew
+                    vec![Event::FocusGained, shift_a.clone()],
+                ],
+            );
+            assert_eq!(
+                input,
+                [
+                    pressed.clone(),
+                    released.clone(),
+                    [pressed.clone(), released.clone()].concat(),
+                    pressed.clone(),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn emulated_releases_follow_timeout() {
+        let settings = TerminalSettings {
+            key_release_emulation: KeyReleaseEmulation::Always,
+            key_release_timeout: KeyReleaseTimeout {
+                after_press: FRAME_TIME * 2,
+                after_repeat: FRAME_TIME,
+            },
+            ..default()
+        };
+        let a = Event::Key(KeyEvent::new(TermKeyCode::Char('a'), KeyModifiers::NONE));
+        let input = key_input(
+            settings,
+            vec![
+                vec![a.clone()],
+                vec![],
+                vec![],
+                vec![a.clone()],
+                // Repeats hold the key for less time than the first press
+                vec![a.clone()],
+                vec![],
+            ],
+        );
+        let pressed = vec![(KeyCode::KeyA, ButtonState::Pressed)];
+        let released = vec![(KeyCode::KeyA, ButtonState::Released)];
+        assert_eq!(
+            input,
+            [
+                pressed.clone(),
+                vec![],
+                released.clone(),
+                pressed.clone(),
+                pressed.clone(),
+                released.clone(),
+            ]
+        );
+    }
+
+    #[test]
+    fn modifier_keys_follow_modifier_flags() {
+        let settings = TerminalSettings {
+            key_release_emulation: KeyReleaseEmulation::Never,
+            ..default()
+        };
+        let key =
+            |code, modifiers, kind| Event::Key(KeyEvent::new_with_kind(code, modifiers, kind));
+        let input = key_input(
+            settings,
+            vec![
+                vec![key(
+                    TermKeyCode::Char('c'),
+                    KeyModifiers::CONTROL,
+                    KeyEventKind::Press,
+                )],
+                vec![key(
+                    TermKeyCode::Char('d'),
+                    KeyModifiers::NONE,
+                    KeyEventKind::Press,
+                )],
+                vec![key(
+                    TermKeyCode::Char('e'),
+                    KeyModifiers::CONTROL | KeyModifiers::ALT,
+                    KeyEventKind::Press,
+                )],
+                // Modifiers let go along with a key are released after it
+                vec![key(
+                    TermKeyCode::Char('e'),
+                    KeyModifiers::NONE,
+                    KeyEventKind::Release,
+                )],
+                // Modifier keys reported themselves are not pressed again by the flags
+                vec![
+                    key(
+                        TermKeyCode::Modifier(ModifierKeyCode::RightShift),
+                        KeyModifiers::SHIFT,
+                        KeyEventKind::Press,
+                    ),
+                    key(
+                        TermKeyCode::Char('F'),
+                        KeyModifiers::SHIFT,
+                        KeyEventKind::Press,
+                    ),
+                ],
+                vec![key(
+                    TermKeyCode::Char('g'),
+                    KeyModifiers::NONE,
+                    KeyEventKind::Press,
+                )],
+            ],
+        );
+        assert_eq!(
+            input,
+            [
+                vec![
+                    (KeyCode::ControlLeft, ButtonState::Pressed),
+                    (KeyCode::KeyC, ButtonState::Pressed),
+                ],
+                vec![
+                    (KeyCode::ControlLeft, ButtonState::Released),
+                    (KeyCode::KeyD, ButtonState::Pressed),
+                ],
+                vec![
+                    (KeyCode::ControlLeft, ButtonState::Pressed),
+                    (KeyCode::AltLeft, ButtonState::Pressed),
+                    (KeyCode::KeyE, ButtonState::Pressed),
+                ],
+                vec![
+                    (KeyCode::KeyE, ButtonState::Released),
+                    (KeyCode::ControlLeft, ButtonState::Released),
+                    (KeyCode::AltLeft, ButtonState::Released),
+                ],
+                vec![
+                    (KeyCode::ShiftRight, ButtonState::Pressed),
+                    (KeyCode::KeyF, ButtonState::Pressed),
+                ],
+                vec![
+                    (KeyCode::ShiftRight, ButtonState::Released),
+                    (KeyCode::KeyG, ButtonState::Pressed),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn arrival_orders_input_across_terminals() {
+        let mut app = input_app(Size::new(4, 1), default());
+        let terminal = Terminal::new(
+            &TerminalTarget::Headless(Size::new(4, 1)),
+            &TerminalSettings::default(),
+        )
+        .expect("Failed to set up terminal");
+        let secondary = app.app_mut().world_mut().spawn(terminal).id();
+        app.step(1);
+
+        let world = app.app_mut().world_mut();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        let key = Event::Key(KeyEvent::new(TermKeyCode::Char('a'), KeyModifiers::NONE));
+        let mouse = Event::Mouse(MouseEvent {
+            kind: MouseEventKind::Moved,
+            column: 1,
+            row: 0,
+            modifiers: KeyModifiers::NONE,
+        });
+        let injected = [
+            (primary, key.clone()),
+            (secondary, mouse.clone()),
+            (secondary, key.clone()),
+            (primary, mouse.clone()),
+            (primary, key.clone()),
+        ];
+        for (entity, event) in injected.clone() {
+            world
+                .get::<Terminal>(entity)
+                .expect("Terminal does not exist")
+                .inject_input(event);
+        }
+        app.step(1);
+
+        let mut received = app
+            .app_mut()
+            .world_mut()
+            .resource_mut::<Events<TerminalInputEvent>>()
+            .drain()
+            .collect::<Vec<_>>();
+        // Events from each terminal are in arrival order
+        for terminal in [primary, secondary] {
+            let sequences = received
+                .iter()
+                .filter(|event| event.terminal == terminal)
+                .map(|event| event.arrival.sequence)
+                .collect::<Vec<_>>();
+            assert!(sequences.is_sorted());
+        }
+        received.sort_by_key(|event| event.arrival);
+        let received = received
+            .into_iter()
+            .map(|event| (event.terminal, event.event))
+            .collect::<Vec<_>>();
+        assert_eq!(received, injected);
+    }
+
+    #[test]
+    fn focus_loss_releases_pressed_buttons() {
+        let mut app = input_app(Size::new(4, 1), default());
+        app.step(1);
+        let world = app.app_mut().world_mut();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        let terminal = world
+            .get::<Terminal>(primary)
+            .expect("Primary terminal does not exist");
+        // The button is pressed in the same frame that focus is lost in
+        terminal.inject_input(Event::Mouse(MouseEvent {
+            kind: MouseEventKind::Down(TermMouseButton::Left),
+            column: 1,
+            row: 0,
+            modifiers: KeyModifiers::NONE,
+        }));
+        terminal.inject_input(Event::FocusLost);
+        app.step(1);
+
+        let buttons = app
+            .app_mut()
+            .world_mut()
+            .resource_mut::<Events<MouseButtonInput>>()
+            .drain()
+            .map(|event| (event.button, event.state, event.window))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            buttons,
+            [
+                (MouseButton::Left, ButtonState::Pressed, primary),
+                (MouseButton::Left, ButtonState::Released, primary),
+            ]
+        );
+        let world = app.app().world();
+        assert!(!world
+            .resource::<ButtonInput<MouseButton>>()
+            .pressed(MouseButton::Left));
+    }
+
+    #[test]
+    fn only_primary_terminal_has_window() {
+        let mut app = input_app(Size::new(10, 4), default());
+        let terminal = Terminal::new(
+            &TerminalTarget::Headless(Size::new(6, 2)),
+            &TerminalSettings::default(),
+        )
+        .expect("Failed to set up terminal");
+        let secondary = app.app_mut().world_mut().spawn(terminal).id();
+        app.step(1);
+
+        let world = app.app_mut().world_mut();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        let window = world
+            .query_filtered::<&Window, With<PrimaryWindow>>()
+            .get(world, primary)
+            .expect("Primary terminal has no primary window");
+        assert_eq!(window.physical_size(), UVec2::new(20, 16));
+        assert!(world.get::<Window>(secondary).is_none());
+    }
+
+    #[test]
+    fn mouse_input_is_mapped_to_cell_centres() {
+        let mut app = input_app(Size::new(10, 4), default());
+        let terminal = Terminal::new(
+            &TerminalTarget::Headless(Size::new(6, 2)),
+            &TerminalSettings::default(),
+        )
+        .expect("Failed to set up terminal");
+        let secondary = app.app_mut().world_mut().spawn(terminal).id();
+        app.step(1);
+
+        let mouse = |kind, column, row| {
+            Event::Mouse(MouseEvent {
+                kind,
+                column,
+                row,
+                modifiers: KeyModifiers::NONE,
+            })
+        };
+        let world = app.app_mut().world_mut();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        let terminal = |entity| {
+            world
+                .get::<Terminal>(entity)
+                .expect("Terminal does not exist")
+        };
+        terminal(primary).inject_input(mouse(MouseEventKind::Moved, 0, 0));
+        terminal(primary).inject_input(mouse(MouseEventKind::Down(TermMouseButton::Left), 3, 1));
+        terminal(secondary).inject_input(mouse(MouseEventKind::Down(TermMouseButton::Left), 5, 1));
+        app.step(1);
+
+        let world = app.app_mut().world_mut();
+        let moves = world
+            .resource_mut::<Events<CursorMoved>>()
+            .drain()
+            .map(|event| (event.window, event.position, event.delta))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            moves,
+            [
+                (primary, Vec2::new(1.0, 2.0), None),
+                (primary, Vec2::new(7.0, 6.0), Some(Vec2::new(6.0, 4.0))),
+            ]
+        );
+        let buttons = world
+            .resource_mut::<Events<MouseButtonInput>>()
+            .drain()
+            .map(|event| event.window)
+            .collect::<Vec<_>>();
+        assert_eq!(buttons, [primary]);
+        // Terminals without a window still track the cursor
+        let cursor = world
+            .get::<TerminalCursor>(secondary)
+            .expect("Terminal has no cursor");
+        assert_eq!(cursor.cell, Some(UVec2::new(5, 1)));
+        assert_eq!(cursor.position, Some(Vec2::new(11.0, 6.0)));
+    }
+}
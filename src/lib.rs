@@ -3,23 +3,54 @@
 //! Bevy plugin which allows a camera to render to a terminal window.
 
 use std::{
-    fs::OpenOptions, io::{stdout, Write}, path::PathBuf, sync::{Arc, Mutex}
+    fs::OpenOptions,
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, Once},
+    time::Duration,
 };
 
 use bevy::{
+    asset::{embedded_asset, AssetEvents},
+    core_pipeline::core_3d::graph::{Core3d, Node3d},
+    input::{
+        keyboard::{KeyboardFocusLost, KeyboardInput},
+        mouse::{MouseButtonInput, MouseMotion, MouseWheel},
+        InputSystem,
+    },
     log::{
         tracing_subscriber::{self, layer::SubscriberExt, EnvFilter, Layer, Registry},
         Level,
-    }, prelude::*, utils::tracing::subscriber,
+    },
+    prelude::*,
+    render::{
+        extract_component::ExtractComponentPlugin,
+        render_graph::{RenderGraphApp, ViewNodeRunner},
+        RenderApp,
+    },
+    utils::tracing::subscriber,
+    window::{
+        CursorEntered, CursorLeft, PrimaryWindow, RequestRedraw, WindowFocused, WindowResized,
+    },
 };
-use bevy_dither_post_process::DitherPostProcessPlugin;
+use bevy_dither_post_process::{DitherPostProcessPlugin, DitherRenderLabel};
 
 use bevy_headless_render::HeadlessRenderPlugin;
 use color_eyre::config::HookBuilder;
 pub use crossterm;
-use crossterm::{event::{DisableMouseCapture, PopKeyboardEnhancementFlags}, terminal::{disable_raw_mode, LeaveAlternateScreen}, ExecutableCommand};
+use crossterm::event::KeyboardEnhancementFlags;
+use display::{
+    backend::BoxedBackend,
+    resources::{AdaptiveQuality, RedrawMode},
+};
+use input::resources::KeyboardLayout;
 use once_cell::sync::Lazy;
 pub use ratatui;
+use ratatui::{
+    backend::{Backend, TestBackend},
+    layout::Size,
+    Viewport,
+};
 
 /// Functions and types related to capture and display of world to terminal
 pub mod display;
@@ -30,86 +61,424 @@ pub mod input;
 /// Functions and types related to constructing and rendering TUI widgets
 pub mod widgets;
 
+/// Utilities for testing what is drawn to the terminal, enabled by the `testing` feature
+#[cfg(feature = "testing")]
+pub mod testing;
+
+mod tty;
+
+static GLOBAL_SETUP: Once = Once::new();
+
 static LOG_PATH: Lazy<Arc<Mutex<PathBuf>>> = Lazy::new(|| Arc::new(Mutex::new(PathBuf::default())));
 
 /// Plugin providing terminal display functionality
 pub struct TerminalDisplayPlugin {
     /// Path to redirect tracing logs to. Defaults to "debug.log"
     pub log_path: PathBuf,
+    /// Where to write output and read input. Defaults to [`TerminalTarget::Stdout`]
+    pub target: TerminalTarget,
+    /// How the terminal is set up when the plugin starts, and restored when it exits
+    pub settings: TerminalSettings,
+    /// When to redraw the terminal. Defaults to [`RedrawMode::OnChange`]
+    pub redraw_mode: RedrawMode,
+    /// How output quality is reduced when the terminal cannot keep up. Enabled by default
+    pub adaptive_quality: AdaptiveQuality,
+    /// Layout used to find the physical keys of characters typed in terminals which do not
+    /// report them. Defaults to [`KeyboardLayout::us`]
+    pub keyboard_layout: KeyboardLayout,
 }
 
 impl Default for TerminalDisplayPlugin {
     fn default() -> Self {
         Self {
             log_path: "debug.log".into(),
+            target: default(),
+            settings: default(),
+            redraw_mode: default(),
+            adaptive_quality: default(),
+            keyboard_layout: default(),
+        }
+    }
+}
+
+/// How a terminal is set up. Only the modes which are enabled here are disabled again when the
+/// terminal is restored, whether on exit or after a panic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerminalSettings {
+    /// Area of the terminal to draw in. Inline and fixed viewports are drawn in the normal
+    /// scrollback rather than the alternate screen. Defaults to [`Viewport::Fullscreen`]
+    pub viewport: Viewport,
+    /// Whether to wrap each frame in synchronized update sequences. Defaults to
+    /// [`SynchronizedOutput::Auto`]
+    pub synchronized_output: SynchronizedOutput,
+    /// Whether to enable raw mode, so that input is received key by key without being echoed.
+    /// Without raw mode the terminal is not queried, so keyboard enhancement and automatic
+    /// synchronized output stay disabled, and inline viewports start at the bottom of the
+    /// terminal. Defaults to true
+    pub raw_mode: bool,
+    /// Whether to capture mouse events. Defaults to true
+    pub mouse_capture: bool,
+    /// Keyboard enhancement flags to push, if the terminal reports support for the kitty keyboard
+    /// protocol. Nothing is pushed if the flags are empty. Defaults to
+    /// [`KEYBOARD_ENHANCEMENT_FLAGS`], which reports every key with its event type and physical
+    /// position
+    pub keyboard_enhancement: KeyboardEnhancementFlags,
+    /// Whether to synthesise key releases, for terminals which only report key presses. Defaults
+    /// to [`KeyReleaseEmulation::Auto`]
+    pub key_release_emulation: KeyReleaseEmulation,
+    /// How long keys are held for when key releases are synthesised
+    pub key_release_timeout: KeyReleaseTimeout,
+    /// Whether to enable bracketed paste, so that pasted text arrives as a single
+    /// [`Event::Paste`](crossterm::event::Event::Paste). Defaults to false
+    pub bracketed_paste: bool,
+    /// Whether to enable focus reporting, so that the terminal sends
+    /// [`Event::FocusGained`](crossterm::event::Event::FocusGained) and
+    /// [`Event::FocusLost`](crossterm::event::Event::FocusLost). Keys and mouse buttons which are
+    /// held are released when focus is lost, since their releases would not be reported. Defaults
+    /// to true
+    pub focus_reporting: bool,
+    /// Whether to keep the cursor visible while frames do not place it. Defaults to false
+    pub cursor_visible: bool,
+    /// Size of the virtual terminal to render to when the target is stdout, but stdout is not a
+    /// terminal, such as in CI or when output is piped. Frames are then kept in memory rather than
+    /// written anywhere. If `None`, setting up the terminal fails instead. Defaults to 80x24
+    pub headless_fallback: Option<Size>,
+}
+
+impl Default for TerminalSettings {
+    fn default() -> Self {
+        Self {
+            viewport: default(),
+            synchronized_output: default(),
+            raw_mode: true,
+            mouse_capture: true,
+            keyboard_enhancement: KEYBOARD_ENHANCEMENT_FLAGS,
+            key_release_emulation: default(),
+            key_release_timeout: default(),
+            bracketed_paste: false,
+            focus_reporting: true,
+            cursor_visible: false,
+            headless_fallback: Some(Size::new(80, 24)),
+        }
+    }
+}
+
+/// Keyboard enhancement flags pushed by default. Besides event types, every key is reported as
+/// an escape sequence along with the key at its position on the base layout, so that physical
+/// keys, keypad keys and modifier keys are told apart.
+pub const KEYBOARD_ENHANCEMENT_FLAGS: KeyboardEnhancementFlags =
+    KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+        .union(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        .union(KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES)
+        .union(KeyboardEnhancementFlags::REPORT_ALTERNATE_KEYS);
+
+/// Whether frames are wrapped in synchronized update sequences (DEC private mode 2026), which
+/// prevents the terminal from showing partially drawn frames
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SynchronizedOutput {
+    /// Use synchronized output if the terminal reports support for it
+    #[default]
+    Auto,
+    /// Always use synchronized output
+    Always,
+    /// Never use synchronized output
+    Never,
+}
+
+/// Whether releases are synthesised for keys, which most terminals do not report unless the
+/// kitty keyboard protocol is enabled with [`KeyboardEnhancementFlags::REPORT_EVENT_TYPES`]. When
+/// they are, keys are held while they are pressed and repeated, and released once they have not
+/// been repeated for the [`KeyReleaseTimeout`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyReleaseEmulation {
+    /// Synthesise releases if the terminal does not report support for keyboard enhancement, or
+    /// event types are not requested from it. Headless terminals never synthesise releases.
+    #[default]
+    Auto,
+    /// Always synthesise releases, as well as passing on any reported by the terminal
+    Always,
+    /// Never synthesise releases
+    Never,
+}
+
+/// How long keys are held for when key releases are synthesised. Terminals report a held key by
+/// repeating its press, first after the operating system's repeat delay, and then at its repeat
+/// rate, so the timeouts should be a little longer than those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyReleaseTimeout {
+    /// How long a key is held after being pressed, unless it is repeated. Defaults to 700 ms
+    pub after_press: Duration,
+    /// How long a key is held after each repeat, unless it is repeated again. Defaults to 100 ms
+    pub after_repeat: Duration,
+}
+
+impl Default for KeyReleaseTimeout {
+    fn default() -> Self {
+        Self {
+            after_press: Duration::from_millis(700),
+            after_repeat: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Terminal the plugin writes its output to and reads input from
+#[derive(Default)]
+pub enum TerminalTarget {
+    /// Write to stdout, and read input from the controlling terminal
+    #[default]
+    Stdout,
+    /// Write to, and read input from, the terminal device at the given path, such as `/dev/pts/3`.
+    /// Stdout is left untouched, so it can be used for piping.
+    Device(PathBuf),
+    /// Write to, and read input from, an already open terminal file descriptor. The descriptor is
+    /// closed once the target and the terminal using it have been dropped.
+    #[cfg(unix)]
+    Fd(std::os::fd::OwnedFd),
+    /// Write to any writer, and read input from the controlling terminal. Use
+    /// [`TerminalTarget::writer`] to construct this.
+    Writer(Mutex<Option<Box<dyn Write + Send>>>),
+    /// Render to a virtual terminal of the given size without writing output or reading input,
+    /// such as for game servers and tests. Frames are drawn to a ratatui [`TestBackend`], and the
+    /// last frame can be read with
+    /// [`Terminal::last_frame`](display::resources::Terminal::last_frame).
+    Headless(Size),
+    /// Draw frames to a ratatui backend, without setting up a terminal or reading input. Input can
+    /// be simulated with [`Terminal::inject_input`](display::resources::Terminal::inject_input).
+    /// Use [`TerminalTarget::backend`] to construct this.
+    Backend(Mutex<Option<BoxedBackend>>),
+    /// Write to an [`OutputCapture`](testing::capture::OutputCapture) of a fixed size, without
+    /// setting up raw mode or reading input. Use
+    /// [`OutputCapture::target`](testing::capture::OutputCapture::target) to construct this.
+    /// Requires the `testing` feature.
+    #[cfg(feature = "testing")]
+    Capture(testing::capture::OutputCapture),
+}
+
+impl TerminalTarget {
+    /// Constructs a target writing to the given writer
+    pub fn writer(writer: impl Write + Send + 'static) -> Self {
+        TerminalTarget::Writer(Mutex::new(Some(Box::new(writer))))
+    }
+
+    /// Constructs a target drawing to the given ratatui backend
+    pub fn backend(backend: impl Backend + Send + Sync + 'static) -> Self {
+        TerminalTarget::Backend(Mutex::new(Some(BoxedBackend::new(backend))))
+    }
+
+    /// Constructs a target drawing to a ratatui [`TestBackend`] of the given size
+    pub fn test(width: u16, height: u16) -> Self {
+        TerminalTarget::backend(TestBackend::new(width, height))
+    }
+
+    /// Size of the virtual terminal to render to instead of the target, if it is headless, or is
+    /// stdout while stdout is not a terminal and a fallback size is given
+    pub(crate) fn headless_size(&self, fallback: Option<Size>) -> Option<Size> {
+        match self {
+            TerminalTarget::Headless(size) => Some(*size),
+            TerminalTarget::Stdout if !io::stdout().is_terminal() => fallback,
+            _ => None,
         }
     }
+
+    /// Fixed size of the terminal the target writes to, if its size cannot be queried
+    pub(crate) fn fixed_size(&self) -> Option<Size> {
+        match self {
+            #[cfg(feature = "testing")]
+            TerminalTarget::Capture(capture) => Some(capture.size()),
+            _ => None,
+        }
+    }
+
+    /// Opens the target, returning a writer for output and the terminal device to read input
+    /// from, if it is not the controlling terminal. A writer target can only be opened once.
+    pub(crate) fn open(&self) -> io::Result<(tty::BoxedWriter, Option<Arc<tty::Tty>>)> {
+        let device = match self {
+            TerminalTarget::Stdout => return Ok((Box::new(io::stdout()), None)),
+            TerminalTarget::Headless(_) | TerminalTarget::Backend(_) => {
+                return Err(io::Error::other("Target does not write to a terminal"));
+            }
+            #[cfg(feature = "testing")]
+            TerminalTarget::Capture(capture) => return Ok((Box::new(capture.clone()), None)),
+            TerminalTarget::Writer(writer) => {
+                return writer
+                    .lock()
+                    .expect("Failed to get lock on terminal writer")
+                    .take()
+                    .map(|writer| (writer, None))
+                    .ok_or_else(|| io::Error::other("Terminal writer has already been used"));
+            }
+            TerminalTarget::Device(path) => tty::Tty::open(path)?,
+            #[cfg(unix)]
+            TerminalTarget::Fd(fd) => tty::Tty::from_fd(fd.try_clone()?),
+        };
+        Ok((Box::new(device.writer()?), Some(Arc::new(device))))
+    }
 }
 
 impl Plugin for TerminalDisplayPlugin {
     fn build(&self, app: &mut App) {
-        *LOG_PATH
-            .lock()
-            .expect("Failed to get lock on log path mutex") = self.log_path.clone();
-        let log_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(
-                LOG_PATH
-                    .lock()
-                    .expect("Failed to get lock on log path mutex")
-                    .clone(),
-            )
+        // Logging and the panic and error hooks are global, so they are only set up by the first
+        // app in the process, such as when several apps are created in tests
+        GLOBAL_SETUP.call_once(|| {
+            *LOG_PATH
+                .lock()
+                .expect("Failed to get lock on log path mutex") = self.log_path.clone();
+            let log_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(
+                    LOG_PATH
+                        .lock()
+                        .expect("Failed to get lock on log path mutex")
+                        .clone(),
+                )
+                .unwrap();
+            let file_layer = tracing_subscriber::fmt::Layer::new()
+                .with_writer(log_file)
+                .with_filter(EnvFilter::builder().parse_lossy(format!(
+                    "{},{}",
+                    Level::INFO,
+                    "wgpu=error,naga=warn"
+                )));
+            let subscriber = Registry::default().with(file_layer);
+            subscriber::set_global_default(subscriber).unwrap();
+
+            let (panic, error) = HookBuilder::default().into_hooks();
+            let panic = panic.into_panic_hook();
+            let error = error.into_eyre_hook();
+
+            color_eyre::eyre::set_hook(Box::new(move |e| {
+                tty::restore_all();
+                error(e)
+            }))
             .unwrap();
-        let file_layer = tracing_subscriber::fmt::Layer::new()
-            .with_writer(log_file)
-            .with_filter(EnvFilter::builder().parse_lossy(format!("{},{}", Level::INFO, "wgpu=error,naga=warn")));
-        let subscriber = Registry::default().with(file_layer);
-        subscriber::set_global_default(subscriber).unwrap();
-
-        let (panic, error) = HookBuilder::default().into_hooks();
-        let panic = panic.into_panic_hook();
-        let error = error.into_eyre_hook();
-
-        color_eyre::eyre::set_hook(Box::new(move |e| {
-            let _ = restore_terminal();
-            error(e)
-        })).unwrap();
-
-        std::panic::set_hook(Box::new(move |info| {
-            let _ = restore_terminal();
-            error!("{info}");
-            panic(info);
-        }));
-        
+
+            std::panic::set_hook(Box::new(move |info| {
+                tty::restore_all();
+                error!("{info}");
+                panic(info);
+            }));
+        });
+
+        // The primary terminal takes the place of the primary window, if `WindowPlugin` has
+        // already spawned one
+        let terminal = display::resources::Terminal::new(&self.target, &self.settings)
+            .or_else(|error| {
+                // Displays and widgets are still drawn headless, rather than the app failing
+                let Some(size) = self.settings.headless_fallback else {
+                    return Err(error);
+                };
+                error!("Failed to set up terminal, rendering headless: {error}");
+                display::resources::Terminal::new(&TerminalTarget::Headless(size), &self.settings)
+            })
+            .expect("Failed to set up terminal");
+        let world = app.world_mut();
+        let primary_window = world
+            .query_filtered::<Entity, With<PrimaryWindow>>()
+            .iter(world)
+            .next();
+        let primary_terminal = match primary_window {
+            Some(window) => world.entity_mut(window).insert(terminal).id(),
+            None => world
+                .spawn((terminal, Window::default(), PrimaryWindow))
+                .id(),
+        };
+        app.insert_resource(display::resources::PrimaryTerminal(primary_terminal));
+
+        // Terminal input is turned into Bevy input events before Bevy processes them, so input
+        // resources such as `ButtonInput` reflect it in the same frame
+        app.add_systems(
+            PreUpdate,
+            (
+                input::systems::input_handling,
+                input::systems::window_handling.after(input::systems::input_handling),
+            )
+                .before(InputSystem),
+        )
+        .add_systems(
+            Update,
+            (
+                display::systems::adapt_output_quality.before(display::systems::print_to_terminal),
+                display::systems::receive_frames.before(display::systems::print_to_terminal),
+                display::systems::print_to_terminal,
+                // Widgets are updated before drawing, so each frame reflects the same update
+                widgets::systems::widget_input_handling.before(display::systems::print_to_terminal),
+                widgets::systems::update_widgets.before(display::systems::print_to_terminal),
+            ),
+        )
+        .insert_resource(self.redraw_mode)
+        .insert_resource(self.adaptive_quality)
+        .insert_resource(self.keyboard_layout.clone())
+        .init_resource::<display::resources::OutputQuality>()
+        .add_event::<input::events::TerminalInputEvent>()
+        .add_event::<RequestRedraw>()
+        // Input and window events are registered by `InputPlugin` and `WindowPlugin` too, but
+        // apps without them still receive terminal input
+        .add_event::<KeyboardInput>()
+        .add_event::<KeyboardFocusLost>()
+        .add_event::<MouseButtonInput>()
+        .add_event::<MouseMotion>()
+        .add_event::<MouseWheel>()
+        .add_event::<CursorMoved>()
+        .add_event::<CursorEntered>()
+        .add_event::<CursorLeft>()
+        .add_event::<WindowFocused>()
+        .add_event::<WindowResized>();
+
+        if app.world().contains_resource::<Assets<Image>>() {
+            app.add_systems(
+                Update,
+                widgets::systems::update_image_widgets.before(display::systems::print_to_terminal),
+            );
+        }
+
+        // Displays are only rendered in apps with a renderer, while widgets are drawn without
+        // one, such as in snapshot tests
+        if app.get_sub_app(RenderApp).is_none() {
+            return;
+        }
+
+        embedded_asset!(app, "../assets/shaders/braille_mask.wgsl");
         app.add_plugins((
             DitherPostProcessPlugin,
             HeadlessRenderPlugin,
+            ExtractComponentPlugin::<display::components::BrailleMaskTarget>::default(),
         ))
-        .add_systems(Startup, input::systems::setup_input)
         .add_systems(
             Update,
             (
-                input::systems::input_handling,
-                display::systems::resize_handling,
-                display::systems::print_to_terminal,
-                widgets::systems::widget_input_handling,
-                widgets::systems::update_widgets,
+                display::systems::resize_handling.after(display::systems::adapt_output_quality),
+                display::systems::camera_view_resize_handling,
             ),
         )
-        .insert_resource(display::resources::Terminal::default())
-        .insert_resource(input::resources::EventQueue::default())
-        .add_event::<input::events::TerminalInputEvent>();
+        // Threshold maps are fixed as soon as their addition is announced, which happens in
+        // `Last`, before they are first extracted
+        .add_systems(
+            Last,
+            display::systems::fix_dither_threshold_maps.after(AssetEvents),
+        );
+
+        app.sub_app_mut(RenderApp)
+            .add_render_graph_node::<ViewNodeRunner<display::nodes::BrailleMaskNode>>(
+                Core3d,
+                display::nodes::BrailleMaskLabel,
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    DitherRenderLabel,
+                    display::nodes::BrailleMaskLabel,
+                    Node3d::EndMainPassPostProcessing,
+                ),
+            );
     }
-}
 
-fn restore_terminal() -> Result<(), Box<dyn std::error::Error>>{
-    disable_raw_mode()?;
-    let mut stdout = stdout();
-    stdout.execute(PopKeyboardEnhancementFlags)?
-        .execute(DisableMouseCapture)?
-        .execute(LeaveAlternateScreen)?
-        .flush()?;
-    Ok(())
+    fn finish(&self, app: &mut App) {
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app.init_resource::<display::resources::BrailleMaskPipeline>();
+    }
 }
@@ -0,0 +1,101 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use ratatui::layout::Size;
+
+use crate::TerminalTarget;
+
+/// Number of lines scrolled off the top of the screen which emulated terminals keep
+const SCROLLBACK_LINES: usize = 1000;
+
+/// Captures everything written to a terminal of a fixed size, so tests can assert on the exact
+/// output of the plugin. Use [`OutputCapture::target`] to write to it, and
+/// [`OutputCapture::emulate`] to see what the output would look like on a terminal.
+///
+/// Captured terminals are not set up for input, and frames are written by a background thread, so
+/// use [`Terminal::wait_for_output`](crate::display::resources::Terminal::wait_for_output) before
+/// inspecting the output.
+#[derive(Clone)]
+pub struct OutputCapture {
+    output: Arc<Mutex<Vec<u8>>>,
+    size: Size,
+}
+
+impl OutputCapture {
+    /// Constructs an empty capture of a terminal of the given size
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            output: Arc::default(),
+            size: Size::new(width, height),
+        }
+    }
+
+    /// Size of the captured terminal
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Target writing to this capture
+    pub fn target(&self) -> TerminalTarget {
+        TerminalTarget::Capture(self.clone())
+    }
+
+    /// Everything written so far
+    pub fn bytes(&self) -> Vec<u8> {
+        self.output
+            .lock()
+            .expect("Failed to get lock on captured output")
+            .clone()
+    }
+
+    /// Everything written since the capture was last taken from, clearing it
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(
+            &mut *self
+                .output
+                .lock()
+                .expect("Failed to get lock on captured output"),
+        )
+    }
+
+    /// Feeds everything written so far into a new [`vt100`] terminal emulator of the captured
+    /// size
+    pub fn emulate(&self) -> vt100::Parser {
+        let mut parser = vt100::Parser::new(self.size.height, self.size.width, SCROLLBACK_LINES);
+        parser.process(&self.bytes());
+        parser
+    }
+}
+
+impl Write for OutputCapture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output
+            .lock()
+            .expect("Failed to get lock on captured output")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::OutputCapture;
+
+    #[test]
+    fn emulates_captured_size() {
+        let mut capture = OutputCapture::new(6, 2);
+        write!(capture, "abcdefgh\x1b[2;5Hx").expect("Failed to write to capture");
+        let emulator = capture.emulate();
+        assert_eq!(emulator.screen().size(), (2, 6));
+        let rows = emulator.screen().rows(0, 6).collect::<Vec<_>>();
+        assert_eq!(rows, ["abcdef", "gh  x"]);
+    }
+}
@@ -0,0 +1,8 @@
+/// Capture of the output written to a terminal
+pub mod capture;
+
+/// Golden-frame snapshot testing of apps drawing to a headless terminal
+pub mod snapshot;
+
+/// Terminal emulator used to interpret captured output
+pub use vt100;
@@ -0,0 +1,253 @@
+use std::{fmt::Write, fs, path::Path, time::Duration};
+
+use bevy::{
+    app::{Plugins, PluginsState},
+    prelude::*,
+    time::TimeUpdateStrategy,
+};
+use crossterm::style::{Attribute, SetAttribute, SetBackgroundColor, SetForegroundColor};
+use ratatui::{
+    buffer::{Buffer, Cell},
+    layout::Size,
+    style::{Color, Modifier},
+    text::Span,
+};
+
+use crate::{
+    display::resources::{PrimaryTerminal, Terminal},
+    TerminalDisplayPlugin, TerminalTarget,
+};
+
+/// Environment variable which, when set to `1`, makes snapshot assertions overwrite their fixtures
+/// with the actual frame instead of comparing against them
+pub const UPDATE_SNAPSHOTS_VAR: &str = "UPDATE_SNAPSHOTS";
+
+/// Timestep used by [`SnapshotApp`] unless another is given
+pub const DEFAULT_TIMESTEP: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Attributes written to ANSI snapshots for each modifier
+const MODIFIER_ATTRIBUTES: [(Modifier, Attribute); 9] = [
+    (Modifier::BOLD, Attribute::Bold),
+    (Modifier::DIM, Attribute::Dim),
+    (Modifier::ITALIC, Attribute::Italic),
+    (Modifier::UNDERLINED, Attribute::Underlined),
+    (Modifier::SLOW_BLINK, Attribute::SlowBlink),
+    (Modifier::RAPID_BLINK, Attribute::RapidBlink),
+    (Modifier::REVERSED, Attribute::Reverse),
+    (Modifier::HIDDEN, Attribute::Hidden),
+    (Modifier::CROSSED_OUT, Attribute::CrossedOut),
+];
+
+/// App with [`TerminalDisplayPlugin`] drawing to a headless terminal, which is stepped with a
+/// fixed timestep so that the frames it draws can be compared against stored snapshots.
+///
+/// The app only uses [`MinimalPlugins`], so widgets are drawn straight into the terminal's
+/// [`TestBackend`](ratatui::backend::TestBackend) without a renderer or GPU, and cameras are not
+/// rendered. Add systems, entities and any other plugins with [`SnapshotApp::app_mut`] before
+/// stepping it.
+pub struct SnapshotApp {
+    app: App,
+}
+
+impl SnapshotApp {
+    /// Constructs an app drawing to a headless terminal of the given size
+    pub fn new(width: u16, height: u16) -> Self {
+        Self::with_plugin(TerminalDisplayPlugin {
+            target: TerminalTarget::Headless(Size::new(width, height)),
+            log_path: std::env::temp_dir().join("bevy_terminal_display_snapshot.log"),
+            ..default()
+        })
+    }
+
+    /// Constructs an app using the given plugin, which should draw to a headless terminal or
+    /// backend
+    pub fn with_plugin(plugin: TerminalDisplayPlugin) -> Self {
+        Self::with_plugins((MinimalPlugins, plugin))
+    }
+
+    /// Constructs an app from the given plugins, which must include a [`TerminalDisplayPlugin`]
+    /// drawing to a headless terminal or backend. Cameras are only rendered if the plugins include
+    /// a renderer, such as [`DefaultPlugins`], before the [`TerminalDisplayPlugin`].
+    pub fn with_plugins<M>(plugins: impl Plugins<M>) -> Self {
+        let mut app = App::new();
+        app.add_plugins(plugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(DEFAULT_TIMESTEP));
+        Self { app }
+    }
+
+    /// Sets the amount of time each step advances the app by
+    pub fn with_timestep(mut self, timestep: Duration) -> Self {
+        self.app
+            .insert_resource(TimeUpdateStrategy::ManualDuration(timestep));
+        self
+    }
+
+    /// The app being stepped
+    pub fn app(&self) -> &App {
+        &self.app
+    }
+
+    /// The app being stepped, mutably
+    pub fn app_mut(&mut self) -> &mut App {
+        &mut self.app
+    }
+
+    /// Runs the given number of frames
+    pub fn step(&mut self, frames: u32) -> &mut Self {
+        if self.app.plugins_state() != PluginsState::Cleaned {
+            while self.app.plugins_state() == PluginsState::Adding {
+                bevy::tasks::tick_global_task_pools_on_main_thread();
+            }
+            self.app.finish();
+            self.app.cleanup();
+        }
+        for _ in 0..frames {
+            self.app.update();
+        }
+        self
+    }
+
+    /// Last frame drawn to the primary terminal
+    pub fn frame(&self) -> &Buffer {
+        let world = self.app.world();
+        let primary = world.resource::<PrimaryTerminal>().0;
+        world
+            .get::<Terminal>(primary)
+            .and_then(Terminal::last_frame)
+            .expect("Primary terminal is not headless")
+    }
+
+    /// Compares the last frame drawn to the primary terminal against a fixture, as in
+    /// [`assert_frame_snapshot`]
+    pub fn assert_snapshot(&self, path: impl AsRef<Path>) {
+        assert_frame_snapshot(self.frame(), path);
+    }
+}
+
+/// Text of a frame, one line per row, with trailing whitespace removed from each row
+pub fn frame_text(frame: &Buffer) -> String {
+    rows(frame)
+        .map(|row| {
+            let text = row.iter().map(|cell| cell.symbol()).collect::<String>();
+            text.trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+        + "\n"
+}
+
+/// Text of a frame including its colours and modifiers as ANSI escape sequences, one line per
+/// row, with the style reset at the end of each row
+pub fn frame_ansi(frame: &Buffer) -> String {
+    let mut output = String::new();
+    for row in rows(frame) {
+        let mut style = None;
+        for cell in row {
+            let cell_style = (cell.fg, cell.bg, cell.modifier);
+            if style != Some(cell_style) {
+                write_style(&mut output, cell);
+                style = Some(cell_style);
+            }
+            output.push_str(cell.symbol());
+        }
+        let _ = write!(output, "{}", SetAttribute(Attribute::Reset));
+        output.push('\n');
+    }
+    output
+}
+
+/// Compares a frame against the fixture at the given path, panicking with a line by line diff if
+/// they differ. Fixtures ending in `.ans` include colours and modifiers, as in [`frame_ansi`],
+/// while any others only contain text, as in [`frame_text`].
+///
+/// If the [`UPDATE_SNAPSHOTS_VAR`] environment variable is set to `1`, the fixture is written
+/// instead.
+pub fn assert_frame_snapshot(frame: &Buffer, path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    let ansi = path.extension().is_some_and(|extension| extension == "ans");
+    let actual = if ansi {
+        frame_ansi(frame)
+    } else {
+        frame_text(frame)
+    };
+
+    if std::env::var(UPDATE_SNAPSHOTS_VAR).is_ok_and(|value| value == "1") {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("Failed to create snapshot directory");
+        }
+        fs::write(path, actual).expect("Failed to write snapshot");
+        return;
+    }
+
+    let Ok(expected) = fs::read_to_string(path) else {
+        panic!(
+            "Snapshot {} does not exist, run with {UPDATE_SNAPSHOTS_VAR}=1 to create it. Actual \
+             frame:\n{actual}",
+            path.display(),
+        );
+    };
+    if expected != actual {
+        panic!(
+            "Frame does not match snapshot {}, run with {UPDATE_SNAPSHOTS_VAR}=1 to update it\n{}",
+            path.display(),
+            diff(&expected, &actual, ansi),
+        );
+    }
+}
+
+/// Rows of a frame, with the blank cells following wide characters removed
+fn rows(frame: &Buffer) -> impl Iterator<Item = Vec<&Cell>> {
+    frame
+        .content
+        .chunks(frame.area.width.max(1) as usize)
+        .map(|row| {
+            let mut cells = Vec::with_capacity(row.len());
+            let mut skip = 0;
+            for cell in row {
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+                cells.push(cell);
+                skip = Span::raw(cell.symbol()).width().saturating_sub(1);
+            }
+            cells
+        })
+}
+
+fn write_style(output: &mut String, cell: &Cell) {
+    let _ = write!(output, "{}", SetAttribute(Attribute::Reset));
+    if cell.fg != Color::Reset {
+        let _ = write!(output, "{}", SetForegroundColor(cell.fg.into()));
+    }
+    if cell.bg != Color::Reset {
+        let _ = write!(output, "{}", SetBackgroundColor(cell.bg.into()));
+    }
+    for (modifier, attribute) in MODIFIER_ATTRIBUTES {
+        if cell.modifier.contains(modifier) {
+            let _ = write!(output, "{}", SetAttribute(attribute));
+        }
+    }
+}
+
+/// Line by line diff of two snapshots, showing only the lines which differ. Escape sequences are
+/// escaped so that they can be read.
+fn diff(expected: &str, actual: &str, escape: bool) -> String {
+    let format = |line: Option<&str>| match line {
+        None => "<missing>".to_string(),
+        Some(line) if escape => line.escape_debug().to_string(),
+        Some(line) => line.to_string(),
+    };
+    let expected = expected.lines().collect::<Vec<_>>();
+    let actual = actual.lines().collect::<Vec<_>>();
+    let mut output = String::new();
+    for line in 0..expected.len().max(actual.len()) {
+        let (expected, actual) = (expected.get(line).copied(), actual.get(line).copied());
+        if expected != actual {
+            let _ = writeln!(output, "line {}:", line + 1);
+            let _ = writeln!(output, "- {}", format(expected));
+            let _ = writeln!(output, "+ {}", format(actual));
+        }
+    }
+    output
+}
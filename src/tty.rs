@@ -0,0 +1,289 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, stdout, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crossterm::{
+    cursor::{MoveTo, Show},
+    event::{
+        DisableBracketedPaste, DisableFocusChange, DisableMouseCapture, PopKeyboardEnhancementFlags,
+    },
+    terminal::{disable_raw_mode, LeaveAlternateScreen},
+    QueueableCommand,
+};
+use once_cell::sync::Lazy;
+use ratatui::layout::Rect;
+
+/// Writer terminal output is written to
+pub(crate) type BoxedWriter = Box<dyn Write + Send>;
+
+/// Terminals which have been set up by the plugin, and must be restored if the app panics
+static ACTIVE_TERMINALS: Lazy<Mutex<Vec<(u64, ActiveTerminal)>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Where a terminal which has been set up by the plugin writes output and reads input
+#[derive(Clone)]
+pub(crate) enum TerminalIo {
+    /// Output is written to stdout, and input is read from the controlling terminal
+    Stdout,
+    /// Output is written to a writer which cannot be used to restore the terminal, and input is
+    /// read from the controlling terminal
+    Writer,
+    /// Output is written to, and input read from, a terminal device
+    Device(Arc<Tty>),
+    /// There is no terminal, and frames are drawn to a ratatui backend
+    Headless,
+}
+
+/// Terminal state changed by the plugin, which has to be undone when the terminal is restored
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TerminalSetup {
+    /// Whether raw mode was enabled
+    pub(crate) raw_mode: bool,
+    /// Whether the alternate screen was entered
+    pub(crate) alternate_screen: bool,
+    /// Area of the viewport drawn to when not using the alternate screen, below which the cursor
+    /// is left so that the final frame stays visible
+    pub(crate) viewport: Option<Rect>,
+    /// Whether mouse capture was enabled
+    pub(crate) mouse_capture: bool,
+    /// Whether keyboard enhancement flags were pushed
+    pub(crate) keyboard_enhancement: bool,
+    /// Whether bracketed paste was enabled
+    pub(crate) bracketed_paste: bool,
+    /// Whether focus reporting was enabled
+    pub(crate) focus_reporting: bool,
+    /// Whether the cursor may have been hidden
+    pub(crate) hide_cursor: bool,
+}
+
+/// Terminal which has been set up by the plugin
+#[derive(Clone)]
+pub(crate) struct ActiveTerminal {
+    pub(crate) io: TerminalIo,
+    pub(crate) setup: TerminalSetup,
+}
+
+impl ActiveTerminal {
+    /// Undoes the setup of the terminal
+    pub(crate) fn restore(&self) -> io::Result<()> {
+        match &self.io {
+            TerminalIo::Stdout => {
+                if self.setup.raw_mode {
+                    disable_raw_mode()?;
+                }
+                restore_output(&mut stdout(), &self.setup)
+            }
+            TerminalIo::Writer if self.setup.raw_mode => disable_raw_mode(),
+            TerminalIo::Writer | TerminalIo::Headless => Ok(()),
+            TerminalIo::Device(tty) => {
+                tty.disable_raw_mode()?;
+                restore_output(&mut tty.writer()?, &self.setup)
+            }
+        }
+    }
+
+    /// Records that this terminal has been set up, returning an id to unregister it with
+    pub(crate) fn register(self) -> u64 {
+        let mut active = ACTIVE_TERMINALS
+            .lock()
+            .expect("Failed to get lock on active terminals");
+        let id = active.last().map_or(0, |(id, _)| id + 1);
+        active.push((id, self));
+        id
+    }
+
+    /// Updates the recorded setup of the terminal with the given id
+    pub(crate) fn update(id: u64, setup: TerminalSetup) {
+        if let Ok(mut active) = ACTIVE_TERMINALS.lock() {
+            for (_, terminal) in active.iter_mut().filter(|(active_id, _)| *active_id == id) {
+                terminal.setup = setup;
+            }
+        }
+    }
+
+    /// Records that the terminal with the given id has been restored
+    pub(crate) fn unregister(id: u64) {
+        if let Ok(mut active) = ACTIVE_TERMINALS.lock() {
+            active.retain(|(active_id, _)| *active_id != id);
+        }
+    }
+}
+
+/// Restores every terminal which is currently set up. Used by the panic and error hooks.
+pub(crate) fn restore_all() {
+    let active = match ACTIVE_TERMINALS.lock() {
+        Ok(active) => active.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    for (_, terminal) in active {
+        let _ = terminal.restore();
+    }
+}
+
+/// Writes the sequences undoing the given terminal setup to the given writer
+pub(crate) fn restore_output(writer: &mut impl Write, setup: &TerminalSetup) -> io::Result<()> {
+    if setup.keyboard_enhancement {
+        writer.queue(PopKeyboardEnhancementFlags)?;
+    }
+    if setup.mouse_capture {
+        writer.queue(DisableMouseCapture)?;
+    }
+    if setup.bracketed_paste {
+        writer.queue(DisableBracketedPaste)?;
+    }
+    if setup.focus_reporting {
+        writer.queue(DisableFocusChange)?;
+    }
+    if let Some(viewport) = setup.viewport {
+        writer.queue(MoveTo(0, viewport.bottom().saturating_sub(1)))?;
+        writer.write_all(b"\r\n")?;
+    }
+    if setup.alternate_screen {
+        writer.queue(LeaveAlternateScreen)?;
+    }
+    if setup.hide_cursor {
+        writer.queue(Show)?;
+    }
+    writer.flush()
+}
+
+/// Terminal device other than the controlling terminal, such as another pseudoterminal
+pub(crate) struct Tty {
+    file: File,
+    #[cfg(unix)]
+    original_mode: Mutex<Option<libc::termios>>,
+}
+
+impl Tty {
+    /// Opens the terminal device at the given path for reading and writing
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Uses an already open terminal file descriptor, which is closed when the device is dropped
+    #[cfg(unix)]
+    pub(crate) fn from_fd(fd: std::os::fd::OwnedFd) -> Self {
+        Self::new(File::from(fd))
+    }
+
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            #[cfg(unix)]
+            original_mode: Mutex::new(None),
+        }
+    }
+
+    /// New handle to the device for writing output
+    pub(crate) fn writer(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// New handle to the device for reading input
+    pub(crate) fn reader(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Raw file descriptor of the device
+    #[cfg(unix)]
+    pub(crate) fn raw_fd(&self) -> std::os::fd::RawFd {
+        use std::os::fd::AsRawFd;
+
+        self.file.as_raw_fd()
+    }
+
+    /// Switches the device to raw mode, remembering its previous mode
+    pub(crate) fn enable_raw_mode(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let mut original_mode = self
+                .original_mode
+                .lock()
+                .expect("Failed to get lock on terminal mode");
+            if original_mode.is_some() {
+                return Ok(());
+            }
+            let fd = self.raw_fd();
+            // SAFETY: termios is plain data, and is fully initialized by tcgetattr
+            let mut mode = unsafe { std::mem::zeroed::<libc::termios>() };
+            if unsafe { libc::tcgetattr(fd, &mut mode) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = mode;
+            unsafe { libc::cfmakeraw(&mut mode) };
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &mode) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            *original_mode = Some(original);
+        }
+        Ok(())
+    }
+
+    /// Restores the mode the device was in before raw mode was enabled
+    pub(crate) fn disable_raw_mode(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let mut original_mode = match self.original_mode.lock() {
+                Ok(original_mode) => original_mode,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if let Some(mode) = original_mode.take() {
+                if unsafe { libc::tcsetattr(self.raw_fd(), libc::TCSANOW, &mode) } == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for input to become available on the device. Returns false if none
+    /// arrived in time.
+    pub(crate) fn poll_input(&self, timeout: Duration) -> io::Result<bool> {
+        #[cfg(unix)]
+        {
+            let mut poll_fd = libc::pollfd {
+                fd: self.raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+            match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+                -1 => Err(io::Error::last_os_error()),
+                0 => Ok(false),
+                _ => Ok(true),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            // Reads simply block until input arrives
+            let _ = timeout;
+            Ok(true)
+        }
+    }
+
+    /// Size of the device in columns and rows
+    pub(crate) fn size(&self) -> io::Result<(u16, u16)> {
+        #[cfg(unix)]
+        {
+            let mut size = libc::winsize {
+                ws_row: 0,
+                ws_col: 0,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            if unsafe { libc::ioctl(self.raw_fd(), libc::TIOCGWINSZ, &mut size) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok((size.ws_col, size.ws_row))
+        }
+        #[cfg(not(unix))]
+        {
+            crossterm::terminal::size()
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use bevy::prelude::*;
+use ratatui::{buffer::Buffer, layout::Rect, Frame};
+
+use crate::display::components::TerminalDisplayOutput;
+
+use super::TerminalWidget;
+
+/// Ratatui widget which draws the output of a terminal display into an arbitrary area, allowing
+/// cameras to be placed inside custom TUI layouts.
+///
+/// Whenever the view is rendered, the display's render target is resized to fit the area it was
+/// given. A display which has been rendered through a view is no longer drawn fullscreen, until the
+/// [`Widget`](super::components::Widget) which the view is the root of is removed.
+#[derive(Clone)]
+pub struct CameraView {
+    output: TerminalDisplayOutput,
+}
+
+impl CameraView {
+    /// Constructs a new view of the display which the given output belongs to
+    pub fn new(output: &TerminalDisplayOutput) -> Self {
+        Self {
+            output: output.clone(),
+        }
+    }
+
+    /// Entity of the display shown by this view
+    pub fn display(&self) -> Entity {
+        self.output.entity
+    }
+
+    /// Stops the display from being embedded, so that it is drawn fullscreen again
+    pub(crate) fn detach(&self) {
+        *self
+            .output
+            .viewport
+            .lock()
+            .expect("Failed to get lock on display viewport") = None;
+    }
+}
+
+impl ratatui::widgets::Widget for &CameraView {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        *self
+            .output
+            .viewport
+            .lock()
+            .expect("Failed to get lock on display viewport") =
+            Some(UVec2::new(area.width as u32 * 2, area.height as u32 * 4));
+
+        self.output.draw(area, buf);
+    }
+}
+
+impl ratatui::widgets::Widget for CameraView {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        (&self).render(area, buf);
+    }
+}
+
+impl TerminalWidget for CameraView {
+    fn render(&mut self, frame: &mut Frame, rect: Rect) {
+        frame.render_widget(&*self, rect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy_headless_render::components::HeadlessRenderDestination;
+
+    use crate::{
+        display::components::TerminalDisplayOutput, testing::snapshot::SnapshotApp,
+        widgets::components::Widget,
+    };
+
+    use super::CameraView;
+
+    #[test]
+    fn view_embeds_display_until_removed() {
+        let mut app = SnapshotApp::new(20, 6);
+        let world = app.app_mut().world_mut();
+        let display = world.spawn_empty().id();
+        let output = TerminalDisplayOutput {
+            entity: display,
+            destination: HeadlessRenderDestination(default()),
+            frames: default(),
+            viewport: default(),
+            render_target: default(),
+            mask_target: None,
+        };
+        world.entity_mut(display).insert(output.clone());
+        let view = world
+            .spawn(Widget {
+                widget: Box::new(CameraView::new(&output)),
+                depth: 0,
+                enabled: true,
+            })
+            .id();
+        app.step(1);
+
+        // The display is sized to the area of the view, in pixels of braille characters
+        assert!(output.is_embedded());
+        assert_eq!(
+            *output
+                .viewport
+                .lock()
+                .expect("Failed to get lock on display viewport"),
+            Some(UVec2::new(40, 24))
+        );
+
+        app.app_mut().world_mut().despawn(view);
+        assert!(!output.is_embedded());
+        app.step(1);
+        assert!(!output.is_embedded());
+    }
+}
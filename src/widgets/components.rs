@@ -1,9 +1,15 @@
-use bevy::prelude::*;
+use bevy::{
+    ecs::{component::ComponentId, world::DeferredWorld},
+    prelude::*,
+};
 
-use super::TerminalWidget;
+use crate::display::components::TerminalDisplayOutput;
+
+use super::{camera_view::CameraView, TerminalWidget};
 
 /// Component representing a terminal widget.
 #[derive(Component)]
+#[component(on_remove = on_remove_widget)]
 pub struct Widget {
     /// The widget instance itself, containing rendering and input logic
     pub widget: Box<dyn TerminalWidget + Send + Sync>,
@@ -12,3 +18,19 @@ pub struct Widget {
     /// Whether this widget is currently enabled or should be hidden
     pub enabled: bool,
 }
+
+/// Returns the display of a removed [`CameraView`] widget to being drawn fullscreen
+fn on_remove_widget(mut world: DeferredWorld, entity: Entity, _id: ComponentId) {
+    let Some(view) = world.get::<Widget>(entity).and_then(|widget| {
+        let terminal_widget: &dyn TerminalWidget = widget.widget.as_ref();
+        terminal_widget.downcast_ref::<CameraView>()
+    }) else {
+        return;
+    };
+    view.detach();
+    let display = view.display();
+    // Marks the display as changed, so that it is resized to fit its terminal again
+    if let Some(mut output) = world.get_mut::<TerminalDisplayOutput>(display) {
+        output.set_changed();
+    }
+}
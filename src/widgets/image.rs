@@ -0,0 +1,87 @@
+use bevy::prelude::*;
+use ratatui::{layout::Rect, Frame};
+
+use crate::display::encoding::{self, Encoder};
+
+use super::TerminalWidget;
+
+/// Widget which draws an image asset scaled to fit its area, using any of the display encoders.
+///
+/// The image data is kept up to date with its asset while this widget is the root of a
+/// [`Widget`](super::components::Widget) component.
+pub struct ImageWidget {
+    /// Handle of the image asset to draw
+    pub image: Handle<Image>,
+    /// Encoder used to convert the image into characters
+    pub encoder: Encoder,
+    /// Level of Bayer dithering to apply before encoding, if any
+    pub dither: Option<u32>,
+    source: Option<(AssetId<Image>, Image)>,
+    scaled: Option<(UVec2, Encoder, Option<u32>, Image)>,
+}
+
+impl ImageWidget {
+    /// Constructs a new image widget drawing the given image with the given encoder
+    pub fn new(image: Handle<Image>, encoder: Encoder) -> Self {
+        Self {
+            image,
+            encoder,
+            dither: None,
+            source: None,
+            scaled: None,
+        }
+    }
+
+    /// Sets the level of Bayer dithering to apply before encoding, up to
+    /// [`MAX_DITHER_LEVEL`](encoding::MAX_DITHER_LEVEL)
+    pub fn with_dither(mut self, level: u32) -> Self {
+        self.dither = Some(level);
+        self
+    }
+
+    /// Whether the image data held by this widget is missing or belongs to a different asset
+    pub(crate) fn needs_source(&self) -> bool {
+        self.source
+            .as_ref()
+            .is_none_or(|(id, _)| *id != self.image.id())
+    }
+
+    /// Replaces the image data held by this widget with a grayscale copy of the given image
+    pub(crate) fn set_source(&mut self, image: &Image) {
+        let mut image = image.clone();
+        encoding::ensure_r8unorm(&mut image);
+        self.source = Some((self.image.id(), image));
+        self.scaled = None;
+    }
+}
+
+impl TerminalWidget for ImageWidget {
+    fn render(&mut self, frame: &mut Frame, rect: Rect) {
+        let Some((_, source)) = &self.source else {
+            return;
+        };
+
+        let size = UVec2::new(rect.width as u32, rect.height as u32) * self.encoder.cell_size();
+        if size.x == 0 || size.y == 0 {
+            return;
+        }
+
+        let outdated = self
+            .scaled
+            .as_ref()
+            .is_none_or(|(scaled_size, encoder, dither, _)| {
+                (*scaled_size, *encoder, *dither) != (size, self.encoder, self.dither)
+            });
+        if outdated {
+            let mut scaled = encoding::scale(source, size);
+            if let Some(level) = self.dither {
+                encoding::dither(&mut scaled, level);
+            }
+            self.scaled = Some((size, self.encoder, self.dither, scaled));
+        }
+
+        if let Some((_, _, _, scaled)) = &self.scaled {
+            self.encoder.encode(scaled, rect, frame.buffer_mut());
+        }
+    }
+}
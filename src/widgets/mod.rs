@@ -10,6 +10,12 @@ pub mod components;
 /// Systems for this module
 pub(crate) mod systems;
 
+/// Widget for embedding a terminal display's output in TUI layouts
+pub mod camera_view;
+
+/// Widget for drawing image assets in TUI layouts
+pub mod image;
+
 /// Trait which defines an interface for terminal widgets
 pub trait TerminalWidget: DowncastSync {
     /// Called every frame to render the widget
@@ -18,7 +24,8 @@ pub trait TerminalWidget: DowncastSync {
     /// Called when a terminal input event is invoked to update any state accordingly
     fn handle_events(&mut self, _event: &TerminalInputEvent, _commands: &mut Commands) {}
 
-    /// Called every frame during the Update schedule
+    /// Called every frame during the Update schedule. The widget is marked as changed by each
+    /// update, so the terminal it is drawn on is redrawn to reflect it.
     fn update(&mut self, _time: &Time, _commands: &mut Commands) {}
 }
 impl_downcast!(sync TerminalWidget);
@@ -1,24 +1,147 @@
 use bevy::prelude::*;
 
-use crate::input::events::TerminalInputEvent;
+use crate::{
+    display::{components::OnTerminal, resources::PrimaryTerminal},
+    input::events::TerminalInputEvent,
+};
 
-use super::components::Widget;
+use super::{components::Widget, image::ImageWidget, TerminalWidget};
 
-/// Invokes every enabled widget's `handle_events` methods for each incoming input event
+/// Invokes every enabled widget's `handle_events` methods for each input event incoming from the
+/// terminal it is drawn on
 pub fn widget_input_handling(
-    mut widgets: Query<&mut Widget>,
+    mut widgets: Query<(&mut Widget, Option<&OnTerminal>)>,
+    primary: Res<PrimaryTerminal>,
     mut event_reader: EventReader<TerminalInputEvent>,
     mut commands: Commands,
 ) {
     for event in event_reader.read() {
-        for mut widget in widgets.iter_mut().filter(|widget| widget.enabled) {
+        for (mut widget, _) in widgets.iter_mut().filter(|(widget, on_terminal)| {
+            widget.enabled && primary.resolve(*on_terminal) == event.terminal
+        }) {
             widget.widget.handle_events(event, &mut commands);
         }
     }
 }
 
+/// Invokes every widget's `update` method, which marks each widget as changed
 pub fn update_widgets(mut widgets: Query<&mut Widget>, time: Res<Time>, mut commands: Commands) {
     for mut widget in widgets.iter_mut() {
+        // Widgets may change their output in any update, so each one counts as a change
         widget.widget.update(&time, &mut commands);
     }
 }
+
+/// Copies image asset data into image widgets when they are first created, when their handle
+/// changes, or when their asset is modified
+pub fn update_image_widgets(
+    mut widgets: Query<&mut Widget>,
+    images: Res<Assets<Image>>,
+    mut asset_events: EventReader<AssetEvent<Image>>,
+) {
+    let modified = asset_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    for mut widget in widgets.iter_mut() {
+        // Only access the widget mutably once it is known to need updating, so that it is not
+        // marked as changed every frame
+        let terminal_widget: &dyn TerminalWidget = widget.widget.as_ref();
+        let Some(image_widget) = terminal_widget.downcast_ref::<ImageWidget>() else {
+            continue;
+        };
+        if !image_widget.needs_source() && !modified.contains(&image_widget.image.id()) {
+            continue;
+        }
+        let Some(image) = images.get(&image_widget.image) else {
+            continue;
+        };
+        let terminal_widget: &mut dyn TerminalWidget = widget.widget.as_mut();
+        if let Some(image_widget) = terminal_widget.downcast_mut::<ImageWidget>() {
+            image_widget.set_source(image);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use ratatui::{
+        layout::Rect,
+        widgets::{Block, Paragraph},
+        Frame,
+    };
+
+    use crate::{
+        display::resources::{PrimaryTerminal, Terminal},
+        testing::snapshot::{frame_text, SnapshotApp},
+        widgets::{components::Widget, TerminalWidget},
+    };
+
+    /// Widget counting its updates
+    struct Counter {
+        count: u32,
+    }
+
+    impl TerminalWidget for Counter {
+        fn render(&mut self, frame: &mut Frame, rect: Rect) {
+            frame.render_widget(Paragraph::new(self.count.to_string()), rect);
+        }
+
+        fn update(&mut self, _time: &Time, _commands: &mut Commands) {
+            self.count += 1;
+        }
+    }
+
+    /// Widget drawing the same bordered text every frame
+    struct Panel;
+
+    impl TerminalWidget for Panel {
+        fn render(&mut self, frame: &mut Frame, rect: Rect) {
+            let block = Block::bordered().title("Status");
+            frame.render_widget(Paragraph::new("Ready\n界 wide").block(block), rect);
+        }
+    }
+
+    #[test]
+    fn widgets_are_redrawn_after_update() {
+        let mut app = SnapshotApp::new(8, 1);
+        app.app_mut().world_mut().spawn(Widget {
+            widget: Box::new(Counter { count: 0 }),
+            depth: 0,
+            enabled: true,
+        });
+        app.step(2);
+        let frames_written = |app: &SnapshotApp| {
+            let world = app.app().world();
+            let primary = world.resource::<PrimaryTerminal>().0;
+            world
+                .get::<Terminal>(primary)
+                .expect("Primary terminal does not exist")
+                .stats()
+                .frames_written
+        };
+        let before = frames_written(&app);
+        app.step(5);
+        assert_eq!(frames_written(&app) - before, 5);
+        assert_eq!(frame_text(app.frame()), "7\n");
+    }
+
+    #[test]
+    fn static_widget_matches_snapshot() {
+        let mut app = SnapshotApp::new(14, 5);
+        app.app_mut().world_mut().spawn(Widget {
+            widget: Box::new(Panel),
+            depth: 0,
+            enabled: true,
+        });
+        app.step(2).assert_snapshot(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/widgets/snapshots/static_widget.txt"
+        ));
+    }
+}